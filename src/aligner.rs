@@ -9,19 +9,26 @@ use antlib::{
 };
 use bio::alignment::pairwise::banded::Aligner;
 use bio::alignment::AlignmentOperation::{self, *};
+use bio::pattern_matching::myers::{Myers, MyersBuilder};
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fmt::Debug,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::utils::{read_fasta, revcomp};
+use crate::utils::{
+    iupac_bases, iupac_match, mask_low_qual, read_fasta, revcomp, HIDDEN_BASE, MASK_BASE,
+};
 
 #[derive(Clone)]
 pub struct AntAligner {
     index: AntIndex,
     opts: AntAlignOpts,
-    max_mismatch: usize,
+    params: AlignParams,
+    /// the block sequences to check the IUPAC codes
+    seqs: HashMap<String, Vec<u8>>,
 }
 
 impl Debug for AntAligner {
@@ -32,7 +39,6 @@ impl Debug for AntAligner {
 
 #[derive(Debug, Clone)]
 pub struct BandedAligner {
-    name: String,
     gap_open: i32,
     gap_extend: i32,
     // match_fn: F,
@@ -40,8 +46,43 @@ pub struct BandedAligner {
     mismatch: i32,
     k: usize,
     w: usize,
-    seq: Vec<u8>,
-    max_mismatch: usize,
+    /// all the candidate sequences of the block, sorted by name
+    seqs: Vec<(String, Vec<u8>)>,
+    params: AlignParams,
+}
+
+/// neighbour sequence: (mismatch, index) of every sequence within max_mismatch
+type NeighbourTable = HashMap<Vec<u8>, Vec<(usize, usize)>>;
+
+/// barcode matcher based on the precomputed hamming neighbourhood of every sequence
+#[derive(Debug, Clone)]
+pub struct HashAligner {
+    /// the candidate sequences, sorted by name
+    seqs: Vec<(String, Vec<u8>)>,
+    /// the distinct sequence lengths, every read window of these lengths is looked up
+    lens: Vec<usize>,
+    /// neighbour sequence: (mismatch, index) of every sequence within max_mismatch,
+    /// sorted by mismatch, shared because the block info is cloned for every read
+    table: Arc<NeighbourTable>,
+    params: AlignParams,
+}
+
+/// bit-parallel edit distance aligner for the short sequences (<= 64 bp)
+#[derive(Clone)]
+pub struct MyersAligner {
+    /// the candidate sequences and their precomputed pattern, sorted by name
+    seqs: Vec<(String, Vec<u8>, Myers<u64>)>,
+    params: AlignParams,
+}
+
+impl Debug for MyersAligner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&String> = self.seqs.iter().map(|(name, ..)| name).collect();
+        f.debug_struct("MyersAligner")
+            .field("seqs", &names)
+            .field("params", &self.params)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,12 +95,17 @@ pub struct Alignment {
     pub n_match: usize,
     pub strand: Strand,
     pub operations: Option<Vec<AlignmentOperation>>,
+    pub score: i32,
+    /// the number of candidate sequences sharing the best score, >1 means a tie
+    pub n_best: usize,
+    /// the best score of the other candidate sequences, equal to `score` on a tie
+    pub second_score: Option<i32>,
 }
 
 impl Alignment {
     pub fn to_str(&self) -> String {
         format!(
-            "{}:{}:{}:{}:{}:{}:{:#?}",
+            "{}:{}:{}:{}:{}:{}:{:#?}:{}",
             self.best_index,
             self.index_start,
             self.index_end,
@@ -67,10 +113,43 @@ impl Alignment {
             self.query_end,
             self.n_match,
             self.strand,
+            self.n_best,
         )
     }
 }
 
+impl Alignment {
+    /// the number of substitutions in the alignment
+    pub fn n_subst(&self) -> usize {
+        self.operations
+            .as_ref()
+            .map_or(0, |x| x.iter().filter(|&&x| x == Subst).count())
+    }
+
+    /// the number of inserted and deleted bases in the alignment
+    pub fn n_indel(&self) -> usize {
+        self.operations
+            .as_ref()
+            .map_or(0, |x| x.iter().filter(|&&x| x == Ins || x == Del).count())
+    }
+
+    /// how far the best score is ahead of the second best one, None if no other hit
+    pub fn margin(&self) -> Option<i32> {
+        self.second_score.map(|x| self.score - x)
+    }
+}
+
+/// the best score of the candidates other than `best_index`
+fn second_score<'a>(
+    scores: impl Iterator<Item = (&'a String, i32)>,
+    best_index: &str,
+) -> Option<i32> {
+    scores
+        .filter(|(name, _)| *name != best_index)
+        .map(|(_, score)| score)
+        .max()
+}
+
 pub trait Align {
     fn align(&self, seq: &[u8]) -> Option<Alignment>;
 }
@@ -79,21 +158,48 @@ impl Align for AntAligner {
     fn align(&self, seq: &[u8]) -> Option<Alignment> {
         // self.index.align(x, y)
         // let index = Index::create_from_files("test/index.fa", 1, 1).unwrap();
-        let binding = ant_align(&self.index, seq, &self.opts);
+        // ANT seeds on the read bases, the masked and hidden ones are passed as N,
+        // the masked ones are matched in the rescue
+        let seed_seq: Vec<u8> = seq
+            .iter()
+            .map(|&x| if x == MASK_BASE || x == HIDDEN_BASE { b'N' } else { x })
+            .collect();
+        let binding = ant_align(&self.index, &seed_seq, &self.opts);
         let align = binding.get(0);
         if let Some(align) = align {
-            let gx_aln = align.gx_aln.clone();
-            let n_match = align
-                .gx_aln
-                .operations
-                .iter()
-                .filter(|&&x| x == Match)
-                .count();
+            let mut gx_aln = align.gx_aln.clone();
+            // ANT aligns the read as x and the block sequence as y (reverse complemented on
+            // the minus strand), the substitution compatible with the IUPAC code of the block
+            // or at a masked low quality base of the read is counted as a match
+            if let Some(index_seq) = self.seqs.get(&align.ref_name) {
+                let index_seq = if align.strand {
+                    index_seq.to_owned()
+                } else {
+                    revcomp(index_seq)
+                };
+                let (xstart, ystart) = (gx_aln.xstart, gx_aln.ystart);
+                rescue_subst(&mut gx_aln.operations, |i, j| {
+                    match (index_seq.get(ystart + j), seq.get(xstart + i)) {
+                        (Some(&code), Some(&base)) => iupac_match(code, base),
+                        _ => false,
+                    }
+                });
+            }
+            let n_match = count_match(&gx_aln.operations);
 
             // dbg!(self.max_mismatch);
-            if (gx_aln.ylen - n_match) > self.max_mismatch {
+            if !self.params.accept(n_match, gx_aln.yend - gx_aln.ystart, gx_aln.ylen) {
                 return None;
             }
+            // count the hits whose score equals the best one
+            let mut best_names: Vec<&String> = binding
+                .iter()
+                .filter(|x| x.gx_aln.score == gx_aln.score)
+                .map(|x| &x.ref_name)
+                .collect();
+            best_names.sort();
+            best_names.dedup();
+            let scores = binding.iter().map(|x| (&x.ref_name, x.gx_aln.score));
             // if n_match < self.max
             Some(Alignment {
                 best_index: align.ref_name.clone(),
@@ -108,6 +214,9 @@ impl Align for AntAligner {
                     Strand::Minus
                 },
                 operations: Some(gx_aln.operations),
+                score: gx_aln.score,
+                n_best: best_names.len(),
+                second_score: second_score(scores, &align.ref_name),
             })
         } else {
             None
@@ -116,57 +225,490 @@ impl Align for AntAligner {
 }
 
 impl Align for BandedAligner {
+    /// align the read against every candidate sequence on both strands,
+    /// return the best scoring one and the number of candidates tied with it
     fn align(&self, seq: &[u8]) -> Option<Alignment> {
-        let score = |a: u8, b: u8| if a == b { self.mmatch } else { self.mismatch };
+        let score = |a: u8, b: u8| {
+            if iupac_match(a, b) {
+                self.mmatch
+            } else {
+                self.mismatch
+            }
+        };
         let mut aligner = Aligner::new(self.gap_open, self.gap_extend, score, self.k, self.w);
-        let forward_aln = aligner.local(&self.seq, seq);
-        // let n_match = aln.
-        let forward_n_match = forward_aln
-            .operations
-            .iter()
-            .filter(|&&x| x == Match)
-            .count();
-        let seq_len = forward_aln.xlen;
-        if forward_n_match == seq_len {
-            let aln = forward_aln;
-            return Some(Alignment {
-                best_index: (self.name).to_owned(),
+        let seq_len = seq.len();
+        let seq_rc = revcomp(seq);
+        let mut best: Option<Alignment> = None;
+        let mut n_best = 0;
+        let mut scores = vec![];
+        for (name, index_seq) in self.seqs.iter() {
+            let mut forward_aln = aligner.local(index_seq, seq);
+            let mut revcom_aln = aligner.local(index_seq, &seq_rc);
+            iupac_rescue(&mut forward_aln, index_seq, seq);
+            iupac_rescue(&mut revcom_aln, index_seq, &seq_rc);
+            let forward_n_match = count_match(&forward_aln.operations);
+            let revcom_n_match = count_match(&revcom_aln.operations);
+            let (aln, n_match, strand) = if revcom_aln.score > forward_aln.score
+                || (revcom_aln.score == forward_aln.score && revcom_n_match > forward_n_match)
+            {
+                (revcom_aln, revcom_n_match, Strand::Minus)
+            } else {
+                (forward_aln, forward_n_match, Strand::Plus)
+            };
+
+            if !self.params.accept(n_match, aln.xend - aln.xstart, index_seq.len()) {
+                continue;
+            }
+            // the coordinates of the reverse alignment are converted back to the read
+            let (query_start, query_end) = match strand {
+                Strand::Minus => (seq_len - aln.yend, seq_len - aln.ystart),
+                _ => (aln.ystart, aln.yend),
+            };
+            scores.push((name, aln.score));
+            let cur = Alignment {
+                best_index: name.to_owned(),
                 index_start: aln.xstart,
                 index_end: aln.xend,
-                query_start: aln.ystart,
-                query_end: aln.yend,
-                n_match: forward_n_match,
-                strand: Strand::Plus,
+                query_start,
+                query_end,
+                n_match,
+                strand,
+                score: aln.score,
                 operations: Some(aln.operations),
-            });
+                n_best: 0,
+                second_score: None,
+            };
+            match &best {
+                Some(b) if b.score > cur.score => {}
+                Some(b) if b.score == cur.score => n_best += 1,
+                _ => {
+                    best = Some(cur);
+                    n_best = 1;
+                }
+            }
+        }
+        best.map(|mut x| {
+            x.n_best = n_best;
+            x.second_score = second_score(scores.into_iter(), &x.best_index);
+            x
+        })
+    }
+}
+
+/// the number of matched bases in the alignment operations
+fn count_match(operations: &[AlignmentOperation]) -> usize {
+    operations.iter().filter(|&&x| x == Match).count()
+}
+
+/// turn the substitutions into matches if `is_match` holds for their offsets in x and y
+fn rescue_subst(operations: &mut [AlignmentOperation], is_match: impl Fn(usize, usize) -> bool) {
+    let (mut i, mut j) = (0, 0);
+    for op in operations.iter_mut() {
+        match *op {
+            Match | Subst => {
+                if *op == Subst && is_match(i, j) {
+                    *op = Match;
+                }
+                i += 1;
+                j += 1;
+            }
+            Ins => i += 1,
+            Del => j += 1,
+            Xclip(n) => i += n,
+            Yclip(n) => j += n,
         }
-        let seq = revcomp(seq);
-        let revcom_aln = aligner.local(&self.seq, &seq);
-        let revcom_n_match = revcom_aln
-            .operations
+    }
+}
+
+/// the substitutions of the block sequence (x) compatible with the read (y) by IUPAC code
+fn iupac_rescue(aln: &mut bio::alignment::Alignment, x: &[u8], y: &[u8]) {
+    let (xstart, ystart) = (aln.xstart, aln.ystart);
+    rescue_subst(&mut aln.operations, |i, j| {
+        iupac_match(x[xstart + i], y[ystart + j])
+    });
+}
+
+impl HashAligner {
+    pub fn new(seq_hash: &HashMap<String, Vec<u8>>, params: &AlignParams) -> HashAligner {
+        let mut seqs: Vec<(String, Vec<u8>)> = seq_hash
             .iter()
-            .filter(|&&x| x == Match)
-            .count();
+            .map(|(name, seq)| (name.to_owned(), seq.to_ascii_uppercase()))
+            .collect();
+        seqs.sort();
+        let mut table = NeighbourTable::new();
+        let mut lens = vec![];
+        for (ii, (_, seq)) in seqs.iter().enumerate() {
+            lens.push(seq.len());
+            // the degenerate bases are expanded to every compatible sequence
+            let neighbours = expand_iupac(seq)
+                .iter()
+                .flat_map(|x| hamming_neighbours(x, params.max_mismatch_for(seq.len())))
+                .collect::<Vec<_>>();
+            for (neighbour, mismatch) in neighbours {
+                let entry = table.entry(neighbour).or_default();
+                match entry.iter_mut().find(|x| x.1 == ii) {
+                    Some(hit) => hit.0 = hit.0.min(mismatch),
+                    None => entry.push((mismatch, ii)),
+                }
+            }
+        }
+        table.values_mut().for_each(|x| x.sort());
+        lens.sort();
+        lens.dedup();
+        HashAligner {
+            seqs,
+            lens,
+            table: Arc::new(table),
+            params: params.clone(),
+        }
+    }
+}
 
-        let (aln, n_match, strand) = if (revcom_n_match > forward_n_match) {
-            (revcom_aln, revcom_n_match, Strand::Minus)
-        } else {
-            (forward_aln, forward_n_match, Strand::Plus)
+impl HashAligner {
+    /// look up the window in the neighbourhood table, the masked low quality bases and
+    /// the N bases are filled with every base (at most `MAX_MASKED` of them) and the nearest
+    /// hit of every sequence is kept, sorted by mismatch, every N counts as a mismatch
+    fn lookup(&self, window: &[u8]) -> Option<Cow<'_, [(usize, usize)]>> {
+        const MAX_MASKED: usize = 4;
+        let masked: Vec<usize> = (0..window.len())
+            .filter(|&ii| window[ii] == MASK_BASE || window[ii] == b'N')
+            .collect();
+        if masked.is_empty() {
+            return self.table.get(window).map(|x| Cow::Borrowed(x.as_slice()));
+        }
+        // too many to expand, the block falls back to the unmasked read
+        if masked.len() > MAX_MASKED {
+            return None;
+        }
+        let n_count = masked.iter().filter(|&&ii| window[ii] == b'N').count();
+        let mut best: HashMap<usize, usize> = HashMap::new();
+        for fill in expand_iupac(&vec![b'N'; masked.len()]) {
+            let mut window = window.to_vec();
+            masked.iter().zip(fill).for_each(|(&ii, base)| window[ii] = base);
+            let Some(hits) = self.table.get(&window) else {
+                continue;
+            };
+            for &(mismatch, ii) in hits {
+                let m = best.entry(ii).or_insert(mismatch);
+                *m = (*m).min(mismatch);
+            }
+        }
+        let mut hits: Vec<(usize, usize)> = best
+            .into_iter()
+            .map(|(ii, m)| (m + n_count, ii))
+            .filter(|&(m, ii)| m <= self.params.max_mismatch_for(self.seqs[ii].1.len()))
+            .collect();
+        if hits.is_empty() {
+            return None;
+        }
+        hits.sort();
+        Some(Cow::Owned(hits))
+    }
+
+    /// the closest pair of the sequences sharing a neighbour and their Hamming distance,
+    /// only the pairs within twice the max_mismatch are found
+    pub fn closest_pair(&self) -> Option<(usize, String, String)> {
+        let mut closest: Option<(usize, usize, usize)> = None;
+        for hits in self.table.values().filter(|x| x.len() > 1) {
+            // the sequences are sorted by name, so is the pair
+            for (jj, &(m_a, a)) in hits.iter().enumerate() {
+                for &(m_b, b) in hits[jj + 1..].iter() {
+                    let pair = (m_a + m_b, a.min(b), a.max(b));
+                    if closest.is_none_or(|x| pair < x) {
+                        closest = Some(pair);
+                    }
+                }
+            }
+        }
+        closest.map(|(d, a, b)| (d, self.seqs[a].0.to_owned(), self.seqs[b].0.to_owned()))
+    }
+}
+
+/// all the concrete sequences matched by the IUPAC codes of `seq`
+fn expand_iupac(seq: &[u8]) -> Vec<Vec<u8>> {
+    let mut seqs = vec![vec![]];
+    for code in seq {
+        let bases = match iupac_bases(*code) {
+            b"" => std::slice::from_ref(code),
+            bases => bases,
         };
+        seqs = seqs
+            .iter()
+            .flat_map(|x| {
+                bases.iter().map(move |base| {
+                    let mut x = x.clone();
+                    x.push(*base);
+                    x
+                })
+            })
+            .collect();
+    }
+    seqs
+}
 
-        if (seq_len - n_match) > self.max_mismatch {
-            None
-        } else {
-            Some(Alignment {
-                best_index: (self.name).to_owned(),
-                index_start: aln.xstart,
-                index_end: aln.xend,
-                query_start: aln.ystart,
-                query_end: aln.yend,
-                n_match,
-                strand,
-                operations: Some(aln.operations),
+/// all the sequences within `max_mismatch` substitutions of `seq`, with their distance
+fn hamming_neighbours(seq: &[u8], max_mismatch: usize) -> Vec<(Vec<u8>, usize)> {
+    let mut neighbours = vec![(seq.to_vec(), 0)];
+    let mut last = vec![(seq.to_vec(), 0)];
+    for mismatch in 1..=max_mismatch.min(seq.len()) {
+        let mut cur = vec![];
+        for (neighbour, min_pos) in last.iter() {
+            // only substitute after the last changed position to avoid duplicates
+            for pos in *min_pos..seq.len() {
+                // the N bases of the read are handled in the lookup
+                for &base in b"ACGT" {
+                    if base == seq[pos] {
+                        continue;
+                    }
+                    let mut new = neighbour.clone();
+                    new[pos] = base;
+                    cur.push((new, pos + 1));
+                }
+            }
+        }
+        neighbours.extend(cur.iter().map(|(x, _)| (x.clone(), mismatch)));
+        last = cur;
+    }
+    neighbours
+}
+
+impl Align for HashAligner {
+    /// look up every window of the read on both strands, return the nearest sequence
+    fn align(&self, seq: &[u8]) -> Option<Alignment> {
+        let seq_len = seq.len();
+        let seq = seq.to_ascii_uppercase();
+        let seq_rc = revcomp(&seq);
+        // (mismatch, index, window start, window length, strand)
+        let mut best: Option<(usize, usize, usize, usize, Strand)> = None;
+        let mut best_indexes: Vec<usize> = vec![];
+        // the best score of every sequence
+        let mut scores: HashMap<usize, i32> = HashMap::new();
+        for (strand, query) in [(Strand::Plus, &seq), (Strand::Minus, &seq_rc)] {
+            for &len in self.lens.iter().filter(|&&x| x <= seq_len) {
+                for start in 0..=(seq_len - len) {
+                    let Some(hits) = self.lookup(&query[start..start + len]) else {
+                        continue;
+                    };
+                    for &(mismatch, ii) in hits.iter() {
+                        let score = scores.entry(ii).or_insert(i32::MIN);
+                        *score = (*score).max(len as i32 - 2 * mismatch as i32);
+                    }
+                    let mismatch = hits[0].0;
+                    let indexes: Vec<usize> = hits
+                        .iter()
+                        .take_while(|x| x.0 == mismatch)
+                        .map(|x| x.1)
+                        .collect();
+                    match best {
+                        Some((m, ..)) if m < mismatch => continue,
+                        Some((m, ..)) if m == mismatch => {}
+                        _ => {
+                            best = Some((mismatch, indexes[0], start, len, strand.clone()));
+                            best_indexes.clear();
+                        }
+                    }
+                    for ii in indexes.iter() {
+                        if !best_indexes.contains(ii) {
+                            best_indexes.push(*ii);
+                        }
+                    }
+                }
+            }
+        }
+        let (mismatch, index, start, len, strand) = best?;
+        let query = if strand == Strand::Minus { &seq_rc } else { &seq };
+        let (name, index_seq) = &self.seqs[index];
+        let operations: Vec<AlignmentOperation> = index_seq
+            .iter()
+            .zip(query[start..start + len].iter())
+            .map(|(&a, &b)| if iupac_match(a, b) { Match } else { Subst })
+            .collect();
+        let (query_start, query_end) = match strand {
+            Strand::Minus => (seq_len - start - len, seq_len - start),
+            _ => (start, start + len),
+        };
+        Some(Alignment {
+            best_index: name.to_owned(),
+            index_start: 0,
+            index_end: len,
+            query_start,
+            query_end,
+            n_match: len - mismatch,
+            strand,
+            operations: Some(operations),
+            score: (len - mismatch) as i32 - mismatch as i32,
+            n_best: best_indexes.len(),
+            second_score: second_score(
+                scores.iter().map(|(&ii, &score)| (&self.seqs[ii].0, score)),
+                name,
+            ),
+        })
+    }
+}
+
+impl MyersAligner {
+    pub fn new(seq_hash: &HashMap<String, Vec<u8>>, params: &AlignParams) -> MyersAligner {
+        let mut seqs: Vec<(&String, &Vec<u8>)> = seq_hash.iter().collect();
+        seqs.sort();
+        let seqs = seqs
+            .into_iter()
+            .map(|(name, seq)| {
+                if seq.len() > 64 {
+                    panic!("the sequence {} is longer than 64 bp for MYERS", name);
+                }
+                (name.to_owned(), seq.to_owned(), iupac_myers(seq))
             })
+            .collect();
+        MyersAligner {
+            seqs,
+            params: params.clone(),
+        }
+    }
+}
+
+/// the myers pattern whose degenerate bases match every compatible base
+fn iupac_myers(seq: &[u8]) -> Myers<u64> {
+    let mut builder = MyersBuilder::new();
+    for &code in b"RYSWKMBDHVN" {
+        builder.ambig(code, iupac_bases(code));
+    }
+    builder.text_wildcard(MASK_BASE);
+    builder.build_64(seq.to_ascii_uppercase().iter())
+}
+
+impl Align for MyersAligner {
+    /// find the best edit distance hit of every candidate on both strands,
+    /// only the best one is traced back for the alignment operations
+    fn align(&self, seq: &[u8]) -> Option<Alignment> {
+        let seq_len = seq.len();
+        let seq_rc = revcomp(seq);
+        // (distance, end, candidate, strand)
+        let mut best: Option<(usize, usize, usize, Strand)> = None;
+        let mut n_best = 0;
+        let mut scores = vec![];
+        for (ii, (name, index_seq, myers)) in self.seqs.iter().enumerate() {
+            let (forward_end, forward_dist) = myers.find_best_end(seq);
+            let (revcom_end, revcom_dist) = myers.find_best_end(&seq_rc);
+            let (end, dist, strand) = if revcom_dist < forward_dist {
+                (revcom_end, revcom_dist as usize, Strand::Minus)
+            } else {
+                (forward_end, forward_dist as usize, Strand::Plus)
+            };
+            if dist > self.params.max_mismatch_for(index_seq.len()) {
+                continue;
+            }
+            scores.push((name, index_seq.len() as i32 - 2 * dist as i32));
+            match best {
+                Some((d, ..)) if d < dist => {}
+                Some((d, ..)) if d == dist => n_best += 1,
+                _ => {
+                    best = Some((dist, end, ii, strand));
+                    n_best = 1;
+                }
+            }
+        }
+        let (dist, end, ii, strand) = best?;
+        let (name, index_seq, myers) = &self.seqs[ii];
+        let query = if strand == Strand::Minus { &seq_rc } else { seq };
+        let mut myers = myers.clone();
+        let mut matches = myers.find_all_lazy(query.iter(), dist as u8);
+        // search up to the end position before the traceback
+        matches.by_ref().take_while(|&(x, _)| x < end).count();
+        let mut aln = bio::alignment::Alignment::default();
+        if !matches.alignment_at(end, &mut aln) {
+            return None;
+        }
+        iupac_rescue(&mut aln, index_seq, query);
+        let n_match = count_match(&aln.operations);
+        let (query_start, query_end) = match strand {
+            Strand::Minus => (seq_len - aln.yend, seq_len - aln.ystart),
+            _ => (aln.ystart, aln.yend),
+        };
+        Some(Alignment {
+            best_index: name.to_owned(),
+            index_start: aln.xstart,
+            index_end: aln.xend,
+            query_start,
+            query_end,
+            n_match,
+            strand,
+            score: aln.xlen as i32 - 2 * dist as i32,
+            operations: Some(aln.operations),
+            n_best,
+            second_score: second_score(scores.into_iter(), name),
+        })
+    }
+}
+
+/// the alignment parameters of a block, set by the optional columns of blockinfo
+#[derive(Debug, Clone)]
+pub struct AlignParams {
+    pub max_mismatch: usize,
+    /// the max mismatch per aligned base, used instead of `max_mismatch` if set
+    pub max_error_rate: Option<f64>,
+    /// the min fraction of the block sequence covered by the alignment
+    pub min_aln_frac: Option<f64>,
+    /// the min margin of the best score over the second best one, the closer hit is ambiguous
+    pub min_margin: Option<i32>,
+    /// SW: gap open penalty
+    pub gap_open: i32,
+    /// SW: gap extend penalty
+    pub gap_extend: i32,
+    /// SW: match score
+    pub match_score: i32,
+    /// SW: mismatch score
+    pub mismatch_score: i32,
+    /// SW: kmer match length
+    pub kmer_len: usize,
+    /// SW: window size for creating the band
+    pub band_width: usize,
+    /// ANT: min seed length
+    pub min_seed_len: usize,
+    /// ANT: the score range of the multiple mapping
+    pub multimap_score_range: i32,
+}
+
+impl Default for AlignParams {
+    fn default() -> Self {
+        AlignParams {
+            max_mismatch: 0,
+            max_error_rate: None,
+            min_aln_frac: None,
+            min_margin: None,
+            gap_open: -2,
+            gap_extend: -1,
+            match_score: 1,
+            mismatch_score: -1,
+            kmer_len: 3,
+            band_width: 5,
+            min_seed_len: 11,
+            multimap_score_range: 20,
+        }
+    }
+}
+
+impl AlignParams {
+    /// the max mismatch allowed for a block sequence of `seq_len` bases
+    pub fn max_mismatch_for(&self, seq_len: usize) -> usize {
+        match self.max_error_rate {
+            Some(rate) => (rate * seq_len as f64).floor() as usize,
+            None => self.max_mismatch,
+        }
+    }
+
+    /// check the alignment covering `aligned_len` of the `seq_len` bases of the block sequence,
+    /// the unaligned bases count as mismatches unless the error rate is set
+    pub fn accept(&self, n_match: usize, aligned_len: usize, seq_len: usize) -> bool {
+        if let Some(frac) = self.min_aln_frac {
+            if (aligned_len as f64) < frac * seq_len as f64 {
+                return false;
+            }
+        }
+        match self.max_error_rate {
+            Some(rate) => {
+                aligned_len.saturating_sub(n_match) as f64 <= rate * aligned_len as f64
+            }
+            None => seq_len.saturating_sub(n_match) <= self.max_mismatch,
         }
     }
 }
@@ -175,6 +717,8 @@ impl Align for BandedAligner {
 pub enum BAligner {
     BandedAligner(BandedAligner),
     AntAligner(AntAligner),
+    HashAligner(HashAligner),
+    MyersAligner(MyersAligner),
 }
 
 impl BAligner {
@@ -182,26 +726,27 @@ impl BAligner {
     pub fn new(
         method: AlignMethod,
         seq_hash: &HashMap<String, Vec<u8>>,
-        max_mismatch: usize,
+        params: &AlignParams,
     ) -> BAligner {
         // let seq_hash = read_fasta(fasta_file).unwrap();
 
-        let (seq_name, seq) = seq_hash.iter().next().unwrap();
         match method {
             AlignMethod::SW => {
-                let k = 3; // kmer match length
-                let w = 5; // Window size for creating the band
-                           // let mut aligner = Aligner::new(-5, -1, score, k, w);
+                // let mut aligner = Aligner::new(-5, -1, score, k, w);
+                let mut seqs: Vec<(String, Vec<u8>)> = seq_hash
+                    .iter()
+                    .map(|(name, seq)| (name.to_owned(), seq.to_owned()))
+                    .collect();
+                seqs.sort();
                 let bandedaligner = BandedAligner {
-                    gap_open: -2,
-                    gap_extend: -1,
-                    k,
-                    w,
-                    seq: seq.clone(),
-                    name: seq_name.to_owned(),
-                    mmatch: 1,
-                    mismatch: -1,
-                    max_mismatch,
+                    gap_open: params.gap_open,
+                    gap_extend: params.gap_extend,
+                    k: params.kmer_len,
+                    w: params.band_width,
+                    seqs,
+                    mmatch: params.match_score,
+                    mismatch: params.mismatch_score,
+                    params: params.clone(),
                 };
                 // Box::new(bandedaligner)
                 BAligner::BandedAligner(bandedaligner)
@@ -211,19 +756,22 @@ impl BAligner {
                 // let index = AntIndex::create_from_files(fasta_file, 1, 1).unwrap();
                 let index = AntIndex::create_from_hashmap(seq_hash, 1, 1).unwrap();
                 let align_opts = AntAlignOpts {
-                    min_seed_len: 11,
+                    min_seed_len: params.min_seed_len as _,
                     min_match_counts_percent: 0.0,
                     min_aln_score: 0,
-                    multimap_score_range: 20,
+                    multimap_score_range: params.multimap_score_range as _,
                 };
                 // let align = ant_align(&index, read, &align_opts);
                 let antaligner = AntAligner {
                     index,
                     opts: align_opts,
-                    max_mismatch,
+                    params: params.clone(),
+                    seqs: seq_hash.clone(),
                 };
                 BAligner::AntAligner(antaligner)
             }
+            AlignMethod::HASH => BAligner::HashAligner(HashAligner::new(seq_hash, params)),
+            AlignMethod::MYERS => BAligner::MyersAligner(MyersAligner::new(seq_hash, params)),
         }
     }
 
@@ -232,16 +780,113 @@ impl BAligner {
         match self {
             BAligner::BandedAligner(aligner) => aligner.align(seq),
             BAligner::AntAligner(aligner) => aligner.align(seq),
+            BAligner::HashAligner(aligner) => aligner.align(seq),
+            BAligner::MyersAligner(aligner) => aligner.align(seq),
+        }
+    }
+
+    pub fn params(&self) -> &AlignParams {
+        match self {
+            BAligner::BandedAligner(aligner) => &aligner.params,
+            BAligner::AntAligner(aligner) => &aligner.params,
+            BAligner::HashAligner(aligner) => &aligner.params,
+            BAligner::MyersAligner(aligner) => &aligner.params,
         }
     }
 }
 
+/// match the block sequences hanging off the read start (`at_start`) or end (`at_end`)
+/// without gaps, only the overlapping part is checked against the thresholds,
+/// the overlap is at least `min_aln_frac` (default half) of the block sequence
+pub fn end_clip_align(
+    seq: &[u8],
+    seq_hash: &HashMap<String, Vec<u8>>,
+    params: &AlignParams,
+    at_start: bool,
+    at_end: bool,
+) -> Option<Alignment> {
+    let seq_len = seq.len();
+    let seq_rc = revcomp(seq);
+    let mut names: Vec<&String> = seq_hash.keys().collect();
+    names.sort();
+    let mut best: Option<Alignment> = None;
+    // the best score of every sequence
+    let mut scores: HashMap<&String, i32> = HashMap::new();
+    for name in names {
+        let index_seq = &seq_hash[name];
+        let len = index_seq.len();
+        let min_overlap = (params.min_aln_frac.unwrap_or(0.5) * len as f64).ceil() as usize;
+        for (strand, query) in [(Strand::Plus, seq), (Strand::Minus, seq_rc.as_slice())] {
+            // on the reverse strand the read start is the end of the reverse complement
+            let (hang_start, hang_end) = match strand {
+                Strand::Minus => (at_end, at_start),
+                _ => (at_start, at_end),
+            };
+            for overlap in min_overlap.max(1)..len.min(seq_len + 1) {
+                // (index_start, query_start)
+                let mut candidates = vec![];
+                if hang_start {
+                    candidates.push((len - overlap, 0));
+                }
+                if hang_end {
+                    candidates.push((0, seq_len - overlap));
+                }
+                for (index_start, query_start) in candidates {
+                    let operations: Vec<AlignmentOperation> = index_seq
+                        [index_start..index_start + overlap]
+                        .iter()
+                        .zip(query[query_start..query_start + overlap].iter())
+                        .map(|(&a, &b)| if iupac_match(a, b) { Match } else { Subst })
+                        .collect();
+                    let n_match = count_match(&operations);
+                    if !params.accept(n_match, overlap, overlap) {
+                        continue;
+                    }
+                    let (query_start, query_end) = match strand {
+                        Strand::Minus => (seq_len - query_start - overlap, seq_len - query_start),
+                        _ => (query_start, query_start + overlap),
+                    };
+                    let score = 2 * n_match as i32 - overlap as i32;
+                    let best_score = scores.entry(name).or_insert(score);
+                    *best_score = (*best_score).max(score);
+                    if best.as_ref().is_some_and(|x| x.score >= score) {
+                        continue;
+                    }
+                    best = Some(Alignment {
+                        best_index: name.to_owned(),
+                        index_start,
+                        index_end: index_start + overlap,
+                        query_start,
+                        query_end,
+                        n_match,
+                        strand: strand.clone(),
+                        operations: Some(operations),
+                        score,
+                        n_best: 0,
+                        second_score: None,
+                    });
+                }
+            }
+        }
+    }
+    best.map(|mut x| {
+        // the sequences tied with the best, each counted once
+        x.n_best = scores.values().filter(|&&score| score == x.score).count();
+        x.second_score = second_score(scores.into_iter(), &x.best_index);
+        x
+    })
+}
+
 #[test]
 fn test_baligner() {
     use std::time::{Duration, Instant};
     let now = Instant::now();
     let seq_hash = read_fasta("test/test.fasta").expect("read fasta error");
-    let alingner = BAligner::new(AlignMethod::ANT, &seq_hash, 5);
+    let params = AlignParams {
+        max_mismatch: 5,
+        ..Default::default()
+    };
+    let alingner = BAligner::new(AlignMethod::ANT, &seq_hash, &params);
     // aligner
     // let aligner = get_aligner(AlignMethod::ANT, "test/index.fa");
     let read = b"CACAAAGACAAAAAAAAAAAACCAACAACTACTT";
@@ -290,3 +935,199 @@ fn test_ant() {
     let align = ant_align(&index, read, &align_opts);
     dbg!(align);
 }
+
+#[test]
+fn test_banded_multi_seq() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("bc1".to_string(), b"AAACCTGAGAAACCAT".to_vec());
+    seq_hash.insert("bc2".to_string(), b"GTCACGGGTATATGAG".to_vec());
+    seq_hash.insert("bc3".to_string(), b"GTCACGGGTATATGAG".to_vec());
+    let params = AlignParams {
+        max_mismatch: 2,
+        ..Default::default()
+    };
+    let aligner = BAligner::new(AlignMethod::SW, &seq_hash, &params);
+    let aln = aligner.align(b"TTTTTAAACCTGAGAAACCATTTTTT").unwrap();
+    assert_eq!(aln.best_index, "bc1");
+    assert_eq!((aln.query_start, aln.query_end), (5, 21));
+    assert_eq!(aln.n_best, 1);
+    // the reverse complement hit is reported in the read coordinates
+    let read = revcomp(b"TTTTTGTCACGGGTATATGAGTT");
+    let aln = aligner.align(&read).unwrap();
+    assert_eq!(aln.strand, Strand::Minus);
+    assert_eq!((aln.query_start, aln.query_end), (2, 18));
+    assert_eq!(aln.n_best, 2);
+}
+
+#[test]
+fn test_hash_aligner() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("bc1".to_string(), b"AAACCTGA".to_vec());
+    seq_hash.insert("bc2".to_string(), b"GTCACGGG".to_vec());
+    seq_hash.insert("bc3".to_string(), b"GTCACGCC".to_vec());
+    let params = AlignParams {
+        max_mismatch: 1,
+        ..Default::default()
+    };
+    let aligner = BAligner::new(AlignMethod::HASH, &seq_hash, &params);
+    let aln = aligner.align(b"TTTAAACGTGATTT").unwrap();
+    assert_eq!(aln.best_index, "bc1");
+    assert_eq!((aln.query_start, aln.query_end, aln.n_match), (3, 11, 7));
+    assert_eq!(aln.n_best, 1);
+    let aln = aligner.align(&revcomp(b"TTGTCACGGGTT")).unwrap();
+    assert_eq!((aln.best_index.as_str(), aln.query_start), ("bc2", 2));
+    assert_eq!(aln.strand, Strand::Minus);
+    // one substitution away from both bc2 and bc3
+    let aln = aligner.align(b"TTGTCACGCGTT").unwrap();
+    assert_eq!(aln.n_best, 2);
+    assert!(aligner.align(b"TTTTTTTTTTTT").is_none());
+    // the N base counts as a mismatch
+    let aln = aligner.align(b"TTTAAANCTGATTT").unwrap();
+    assert_eq!((aln.best_index.as_str(), aln.n_match), ("bc1", 7));
+    assert!(aligner.align(b"TTTAAANCTNATTT").is_none());
+    let closest = HashAligner::new(&seq_hash, &params).closest_pair();
+    assert_eq!(closest, Some((2, "bc2".to_string(), "bc3".to_string())));
+    seq_hash.remove("bc3");
+    assert!(HashAligner::new(&seq_hash, &params).closest_pair().is_none());
+}
+
+#[test]
+fn test_myers_aligner() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("aa".to_string(), b"AAACCTGAGAAACCAT".to_vec());
+    seq_hash.insert("bb".to_string(), b"GTCACGGGTATATGAG".to_vec());
+    let params = AlignParams {
+        max_mismatch: 2,
+        ..Default::default()
+    };
+    let aligner = BAligner::new(AlignMethod::MYERS, &seq_hash, &params);
+    // one substitution and one deleted base
+    let aln = aligner.align(b"TTTTTAAACCTCAGAAACAT").unwrap();
+    assert_eq!(aln.best_index, "aa");
+    assert_eq!((aln.query_start, aln.query_end), (5, 20));
+    assert_eq!((aln.n_subst(), aln.n_indel()), (1, 1));
+    let aln = aligner.align(&revcomp(b"TTGTCACGGGTATATGAGTTT")).unwrap();
+    assert_eq!((aln.best_index.as_str(), aln.strand), ("bb", Strand::Minus));
+    assert_eq!((aln.query_start, aln.query_end, aln.n_match), (3, 19, 16));
+    assert!(aligner.align(b"TTTTTTTTTTTTTTTTTTTTTT").is_none());
+}
+
+#[test]
+fn test_iupac_block_seq() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("aa".to_string(), b"AAACCTNNNRAACCAT".to_vec());
+    let params = AlignParams {
+        max_mismatch: 0,
+        ..Default::default()
+    };
+    let read = b"TTTTTAAACCTGCAGAACCATTTTTT";
+    let methods = [AlignMethod::SW, AlignMethod::ANT, AlignMethod::HASH, AlignMethod::MYERS];
+    for method in methods {
+        let aligner = BAligner::new(method, &seq_hash, &params);
+        let aln = aligner.align(read).unwrap();
+        assert_eq!((aln.query_start, aln.query_end, aln.n_match), (5, 21, 16));
+        let aln = aligner.align(&revcomp(read)).unwrap();
+        assert_eq!((aln.strand, aln.n_match), (Strand::Minus, 16));
+        // R does not match C
+        assert!(aligner.align(b"TTTTTAAACCTGCACAACCATTTTTT").is_none());
+    }
+}
+
+#[test]
+fn test_masked_low_qual_base() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("aa".to_string(), b"AAACCTGAGAAACCAT".to_vec());
+    let params = AlignParams {
+        max_mismatch: 0,
+        ..Default::default()
+    };
+    let read = b"TTTTTAAACCTCAGAAACCATTTTTT";
+    let masked = mask_low_qual(read, b"IIIIIIIIIII#IIIIIIIIIIIIII", 10);
+    for method in [AlignMethod::SW, AlignMethod::HASH, AlignMethod::MYERS] {
+        let aligner = BAligner::new(method, &seq_hash, &params);
+        assert!(aligner.align(read).is_none());
+        let aln = aligner.align(&masked).unwrap();
+        assert_eq!((aln.query_start, aln.query_end, aln.n_match), (5, 21, 16));
+    }
+}
+
+#[test]
+fn test_align_params_accept() {
+    let params = AlignParams {
+        max_mismatch: 2,
+        ..Default::default()
+    };
+    assert!(params.accept(28, 30, 30));
+    assert!(!params.accept(5, 5, 30));
+    let params = AlignParams {
+        max_error_rate: Some(0.1),
+        ..Default::default()
+    };
+    assert_eq!((params.max_mismatch_for(8), params.max_mismatch_for(40)), (0, 4));
+    assert!(params.accept(36, 40, 40));
+    assert!(!params.accept(35, 40, 40));
+    assert!(params.accept(5, 5, 30));
+    let params = AlignParams {
+        max_error_rate: Some(0.1),
+        min_aln_frac: Some(0.8),
+        ..Default::default()
+    };
+    assert!(!params.accept(5, 5, 30));
+    assert!(params.accept(24, 25, 30));
+}
+
+#[test]
+fn test_end_clip_align() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("aa".to_string(), b"AAACCTGAGAAACCAT".to_vec());
+    let params = AlignParams {
+        max_mismatch: 1,
+        ..Default::default()
+    };
+    // the first 5 bases of the block are cut off the read start
+    let read = b"TGAGAAACGATTTTTTTTTT";
+    let aln = end_clip_align(read, &seq_hash, &params, true, true).unwrap();
+    assert_eq!((aln.index_start, aln.index_end), (5, 16));
+    assert_eq!((aln.query_start, aln.query_end, aln.n_match), (0, 11, 10));
+    assert!(end_clip_align(read, &seq_hash, &params, false, true).is_none());
+    // the block hangs off the read end on the reverse strand
+    let read = revcomp(b"TTTTTTTTTTAAACCTGAGAAA");
+    let aln = end_clip_align(&read, &seq_hash, &params, true, false).unwrap();
+    assert_eq!(aln.strand, Strand::Minus);
+    assert_eq!((aln.query_start, aln.query_end), (0, 12));
+    // every tied sequence is counted once
+    seq_hash.insert("bb".to_string(), b"AAACCTGAGAAACCAT".to_vec());
+    let aln = end_clip_align(&read, &seq_hash, &params, true, true).unwrap();
+    assert_eq!(aln.n_best, 2);
+}
+
+#[test]
+fn test_second_score() {
+    let mut seq_hash = HashMap::new();
+    seq_hash.insert("aa".to_string(), b"AACCGGTA".to_vec());
+    seq_hash.insert("bb".to_string(), b"AACCGGTC".to_vec());
+    seq_hash.insert("cc".to_string(), b"TTTTTTTT".to_vec());
+    let params = AlignParams {
+        max_mismatch: 1,
+        ..Default::default()
+    };
+    let read = b"GGGGAACCGGTAGGGG";
+    for method in [AlignMethod::SW, AlignMethod::HASH, AlignMethod::MYERS] {
+        let aligner = BAligner::new(method, &seq_hash, &params);
+        let aln = aligner.align(read).unwrap();
+        assert_eq!(aln.best_index, "aa");
+        assert!(aln.margin().unwrap() > 0);
+    }
+    // a tie has no margin
+    seq_hash.insert("dd".to_string(), b"AACCGGTA".to_vec());
+    for method in [AlignMethod::SW, AlignMethod::HASH, AlignMethod::MYERS] {
+        let aligner = BAligner::new(method, &seq_hash, &params);
+        assert_eq!(aligner.align(read).unwrap().margin(), Some(0));
+    }
+    seq_hash.remove("bb");
+    seq_hash.remove("dd");
+    for method in [AlignMethod::SW, AlignMethod::HASH, AlignMethod::MYERS] {
+        let aligner = BAligner::new(method, &seq_hash, &params);
+        assert_eq!(aligner.align(read).unwrap().margin(), None);
+    }
+}
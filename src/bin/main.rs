@@ -7,18 +7,24 @@ use clap::Parser;
 use dashmap::DashMap;
 use legoseq::record::process_record_pair;
 use legoseq::record::process_record_single;
+use legoseq::record::RecordOutputs;
+use legoseq::record::Record;
+use legoseq::readblockalign::{ReadAlignOpts, UmiMode};
+use legoseq::indexstat::{index_hopping, index_pair_counts, index_pair_matrix, IndexStat};
+use legoseq::samplesheet::SampleSheet;
 use minijinja::{Environment, Template};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tracing::info;
+use tracing::{error, info, warn};
 
-use legoseq::blockinfo::{get_block_info_fasta_from_file, BLOCKFLAGS};
-use legoseq::utils::get_reader;
+use legoseq::blockinfo::{get_layouts_from_file, ReadType, BLOCKFLAGS};
+use legoseq::utils::{get_reader, read_name};
 
 #[derive(Parser)]
 #[command(version, author, about, long_about = None)]
@@ -29,15 +35,21 @@ struct Cli {
     /// fastq file, optional
     #[arg(long, value_name = "FILE")]
     in2: Option<String>,
+    /// the index read 1 file, its whole sequence is the block I1, optional
+    #[arg(long, value_name = "FILE")]
+    index1: Option<String>,
+    /// the index read 2 file, its whole sequence is the block I2, optional
+    #[arg(long, value_name = "FILE")]
+    index2: Option<String>,
     /// input type, fasta or fastq, default is fastq
     #[arg(long, value_name = "INPUT_TYPE", default_value = "fastq")]
     input_type: Option<String>,
     /// fasta file
     #[arg(long, value_name = "FILE")]
     fasta: String,
-    /// custom block information file
-    #[arg(long, value_name = "BLOCKINFO")]
-    block_info: String,
+    /// custom block information file, repeat it as NAME=FILE for the alternative layouts
+    #[arg(long, value_name = "BLOCKINFO", required = true)]
+    block_info: Vec<String>,
     /// threads
     #[arg(long, value_name = "FILE")]
     threads: usize,
@@ -54,6 +66,76 @@ struct Cli {
     /// the output file extension, the default is input_type
     #[arg(long, value_name = "EXT")]
     ext: Option<String>,
+    /// search every Fix block only downstream of the previous matched block
+    #[arg(long)]
+    ordered: bool,
+    /// the read bases below this phred quality are not counted as mismatches
+    #[arg(long, value_name = "QUAL")]
+    min_qual: Option<u8>,
+    /// the min number of Fix blocks agreeing with the voted strand of the read
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    min_strand_anchors: usize,
+    /// split the concatemer reads by every occurrence of the blocks, single end or --merge only
+    #[arg(long)]
+    segment: bool,
+    /// merge the overlapping pairs before the block alignment, pair end only
+    #[arg(long)]
+    merge: bool,
+    /// the tsv mapping the Index block hits to the samples, with a sample column
+    /// and the columns named after the Index blocks
+    #[arg(long, value_name = "FILE")]
+    sample_sheet: Option<String>,
+    /// the min overlap of the merged pair
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    min_overlap: usize,
+    /// swap read1 and read2 when the block layout matches read2 better, pair end only
+    #[arg(long)]
+    auto_swap: bool,
+    /// fail instead of warn when the barcodes of an Index block are closer than 2*max_mismatch+1
+    #[arg(long)]
+    strict_index: bool,
+    /// write the UMI blocks in the read header of every output, appended to the read name
+    /// (name) or as the RX:Z/QX:Z tags of the comment (tag)
+    #[arg(long, value_name = "MODE")]
+    umi: Option<UmiMode>,
+}
+
+/// the next records of the index reads, read in lockstep with the read 1,
+/// exit if an index file is shorter than the read 1 or out of sync with it
+fn next_index_records<R: Record, E: Debug, I: Iterator<Item = Result<R, E>>>(
+    index_readers: &mut [(ReadType, I)],
+    r1_id: &str,
+) -> Vec<(ReadType, R)> {
+    index_readers
+        .iter_mut()
+        .map(|(read_type, records)| {
+            let Some(record) = records.next() else {
+                error!("the {:?} reads are fewer than the read 1 at {}", read_type, r1_id);
+                std::process::exit(1);
+            };
+            let record = record.unwrap();
+            if read_name(record.id()) != read_name(r1_id) {
+                error!(
+                    "the {:?} read {} doesn't match the read 1 {}",
+                    read_type,
+                    record.id(),
+                    r1_id
+                );
+                std::process::exit(1);
+            }
+            (read_type.clone(), record)
+        })
+        .collect()
+}
+
+/// exit if an index file has more reads than the read 1
+fn check_index_exhausted<I: Iterator>(index_readers: &mut [(ReadType, I)]) {
+    for (read_type, records) in index_readers.iter_mut() {
+        if records.next().is_some() {
+            error!("the {:?} reads are more than the read 1", read_type);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
@@ -62,13 +144,33 @@ fn main() {
     let outdir = &cli.outdir;
     let r1_file = &cli.in1;
     let r2_file = &cli.in2;
+    let index_files: Vec<(ReadType, String)> =
+        [(ReadType::I1, &cli.index1), (ReadType::I2, &cli.index2)]
+            .into_iter()
+            .filter_map(|(read_type, file)| file.clone().map(|file| (read_type, file)))
+            .collect();
     let input_type = &cli.input_type.unwrap();
     let fasta_file = &cli.fasta;
     let block_info_file = &cli.block_info;
     let prefix = &cli.prefix;
     let template: &Option<String> = &cli.template;
     let ext = &cli.ext.unwrap_or(input_type.to_string());
+    let read_align_opts = ReadAlignOpts {
+        ordered: cli.ordered,
+        min_qual: cli.min_qual,
+        min_strand_anchors: cli.min_strand_anchors,
+        segment: cli.segment,
+        merge: cli.merge,
+        min_overlap: cli.min_overlap,
+        auto_swap: cli.auto_swap,
+        umi: cli.umi,
+    };
     tracing_subscriber::fmt::init();
+    // the pairs are only split after they are merged into single reads
+    if cli.segment && r2_file.is_some() && !cli.merge {
+        error!("--segment is single end only, use it with --merge for the pair end reads");
+        std::process::exit(1);
+    }
     info!("Start");
 
     rayon::ThreadPoolBuilder::new()
@@ -78,6 +180,10 @@ fn main() {
 
     //minijinja
     let template_string = fs::read_to_string(template.clone().unwrap()).expect("无法读取模板文件");
+    // the UMI tags are in the comment, which the rendered read 1 only has if the template writes it
+    if read_align_opts.umi == Some(UmiMode::Tag) && !template_string.contains("read.desc") {
+        warn!("--umi tag: the template doesn't write {{{{ read.desc }}}}, the rendered reads have no UMI tags");
+    }
     // 创建一个新的 MiniJinja 环境
     let env = Environment::new();
     // 从字符串创建一个模板
@@ -92,9 +198,25 @@ fn main() {
     
     // 统计所有 flag 的数目
     let flag_stat_hash: Arc<Mutex<HashMap<usize, usize>>> = Arc::new(Mutex::new(HashMap::new()));
-    let block_info_list = get_block_info_fasta_from_file(block_info_file, fasta_file).unwrap();
+    // the number of reads of every status, e.g. pass/bad_length
+    let status_stat_hash: Arc<Mutex<HashMap<String, usize>>> = Default::default();
+    // the number of reads of every combination of the Index hits
+    let index_stat_hash: Arc<Mutex<IndexStat>> = Default::default();
+    let layouts = get_layouts_from_file(block_info_file, fasta_file, cli.strict_index).unwrap();
+    let sample_sheet = cli.sample_sheet.as_ref().map(|file| {
+        let index_names: Vec<String> = layouts
+            .iter()
+            .flat_map(|x| x.all_block_info())
+            .filter(|x| x.seq_type.is_index())
+            .map(|x| x.idx.to_owned())
+            .collect();
+        SampleSheet::from_file(file, &index_names).expect("无法读取 sample sheet")
+    });
     let out_fq_handle_vec: Arc<Mutex<Vec<File>>>;
     let ud_fq_handle_vec: Arc<Mutex<Vec<File>>>;
+    // the merged pairs are written as single reads
+    let mut merged_fq_handle_vec: Arc<Mutex<Vec<File>>> = Default::default();
+    let mut merged_ud_fq_handle_vec: Arc<Mutex<Vec<File>>> = Default::default();
     let barcode_handle_hash: Arc<Mutex<DashMap<String, Vec<File>>>> = Default::default();
     BLOCKFLAGS.lock().unwrap().iter().for_each(|(k, v)| {
         writeln!(read_info_handle.lock().unwrap(), "#idx:flag={}:{}", k, v).unwrap();
@@ -113,6 +235,14 @@ fn main() {
             File::create(ud_fq_file_r1.clone()).unwrap(),
             File::create(ud_fq_file_r2.clone()).unwrap(),
         ]));
+        if read_align_opts.merge {
+            let merged_fq_file = outdir.join(format!("{}.{}.{}", prefix, "merged.template", ext));
+            let merged_ud_fq_file =
+                outdir.join(format!("{}.{}.{}", prefix, "merged.undetermined", input_type));
+            merged_fq_handle_vec = Arc::new(Mutex::new(vec![File::create(merged_fq_file).unwrap()]));
+            merged_ud_fq_handle_vec =
+                Arc::new(Mutex::new(vec![File::create(merged_ud_fq_file).unwrap()]));
+        }
     } else {
         let out_fq_file_r1 = outdir.join(format!("{}.{}.{}", prefix, "template", ext));
         let ud_fq_file_r1 = outdir.join(format!("{}.{}.{}", prefix, "undetermined", input_type));
@@ -123,92 +253,142 @@ fn main() {
             File::create(ud_fq_file_r1.clone()).unwrap()
         ]));
     }
+    let outputs = RecordOutputs {
+        out_fq_handle_vec,
+        ud_fq_handle_vec,
+        merged_fq_handle_vec,
+        merged_ud_fq_handle_vec,
+        read_info_handle: read_info_handle.clone(),
+        flag_stat_hash: flag_stat_hash.clone(),
+        status_stat_hash: status_stat_hash.clone(),
+        index_stat_hash: index_stat_hash.clone(),
+    };
     if input_type == "fastq" {
         let record_r1 = fastq::Reader::new(get_reader(r1_file)).records();
+        let mut index_readers: Vec<(ReadType, _)> = index_files
+            .iter()
+            .map(|(read_type, file)| {
+                (read_type.clone(), fastq::Reader::new(get_reader(file)).records())
+            })
+            .collect();
         if let Some(r2_file) = r2_file {
             let record_r2 = fastq::Reader::new(get_reader(r2_file)).records();
             record_r1
                 .into_iter()
                 .zip(record_r2.into_iter())
+                .map(|(record_r1, record_r2)| {
+                    let (record_r1, record_r2) = (record_r1.unwrap(), record_r2.unwrap());
+                    let index_records = next_index_records(&mut index_readers, record_r1.id());
+                    (record_r1, record_r2, index_records)
+                })
                 .par_bridge()
-                .for_each(|(record_r1, record_r2)| {
+                .for_each(|(record_r1, record_r2, index_records)| {
                     let mut barcode_handle_hash = barcode_handle_hash.clone();
                     process_record_pair(
-                        record_r1.unwrap(),
-                        record_r2.unwrap(),
+                        record_r1,
+                        record_r2,
+                        &index_records,
                         ext,
-                        &block_info_list,
+                        &layouts,
+                        &read_align_opts,
+                        sample_sheet.as_ref(),
                         prefix,
                         outdir,
                         barcode_handle_hash,
                         template.clone(),
-                        out_fq_handle_vec.clone(),
-                        ud_fq_handle_vec.clone(),
-                        read_info_handle.clone(),
-                        flag_stat_hash.clone(),
+                        outputs.clone(),
                     )
                 })
         } else {
-            record_r1.into_iter().par_bridge().for_each(|record_r1| {
-                let barcode_handle_hash = barcode_handle_hash.clone();
-                process_record_single(
-                    record_r1.unwrap(),
-                    ext,
-                    &block_info_list,
-                    prefix,
-                    outdir,
-                    barcode_handle_hash,
-                    template.clone(),
-                    out_fq_handle_vec.clone(),
-                    ud_fq_handle_vec.clone(),
-                    read_info_handle.clone(),
-                    flag_stat_hash.clone(),
-                )
-            })
+            record_r1
+                .into_iter()
+                .map(|record_r1| {
+                    let record_r1 = record_r1.unwrap();
+                    let index_records = next_index_records(&mut index_readers, record_r1.id());
+                    (record_r1, index_records)
+                })
+                .par_bridge()
+                .for_each(|(record_r1, index_records)| {
+                    let barcode_handle_hash = barcode_handle_hash.clone();
+                    process_record_single(
+                        record_r1,
+                        &index_records,
+                        ext,
+                        &layouts,
+                        &read_align_opts,
+                        sample_sheet.as_ref(),
+                        prefix,
+                        outdir,
+                        barcode_handle_hash,
+                        template.clone(),
+                        outputs.clone(),
+                    )
+                })
         }
+        check_index_exhausted(&mut index_readers);
     }else if input_type == "fasta" {
         let record_r1 = fasta::Reader::new(get_reader(r1_file)).records();
+        let mut index_readers: Vec<(ReadType, _)> = index_files
+            .iter()
+            .map(|(read_type, file)| {
+                (read_type.clone(), fasta::Reader::new(get_reader(file)).records())
+            })
+            .collect();
         if let Some(r2_file) = r2_file {
             let record_r2 = fasta::Reader::new(get_reader(r2_file)).records();
             record_r1
                 .into_iter()
                 .zip(record_r2.into_iter())
+                .map(|(record_r1, record_r2)| {
+                    let (record_r1, record_r2) = (record_r1.unwrap(), record_r2.unwrap());
+                    let index_records = next_index_records(&mut index_readers, record_r1.id());
+                    (record_r1, record_r2, index_records)
+                })
                 .par_bridge()
-                .for_each(|(record_r1, record_r2)| {
+                .for_each(|(record_r1, record_r2, index_records)| {
                     let barcode_handle_hash = barcode_handle_hash.clone();
                     process_record_pair(
-                        record_r1.unwrap(),
-                        record_r2.unwrap(),
+                        record_r1,
+                        record_r2,
+                        &index_records,
                         ext,
-                        &block_info_list,
+                        &layouts,
+                        &read_align_opts,
+                        sample_sheet.as_ref(),
                         prefix,
                         outdir,
                         barcode_handle_hash,
                         template.clone(),
-                        out_fq_handle_vec.clone(),
-                        ud_fq_handle_vec.clone(),
-                        read_info_handle.clone(),
-                        flag_stat_hash.clone(),
+                        outputs.clone(),
                     )
                 })
         } else {
-            record_r1.into_iter().par_bridge().for_each(|record_r1| {
-                let barcode_handle_hash = barcode_handle_hash.clone();
-                process_record_single(
-                    record_r1.unwrap(),
-                    ext,
-                    &block_info_list,
-                    prefix,
-                    outdir,
-                    barcode_handle_hash,
-                    template.clone(),
-                    out_fq_handle_vec.clone(),
-                    ud_fq_handle_vec.clone(),
-                    read_info_handle.clone(),
-                    flag_stat_hash.clone(),
-                )
-            })
+            record_r1
+                .into_iter()
+                .map(|record_r1| {
+                    let record_r1 = record_r1.unwrap();
+                    let index_records = next_index_records(&mut index_readers, record_r1.id());
+                    (record_r1, index_records)
+                })
+                .par_bridge()
+                .for_each(|(record_r1, index_records)| {
+                    let barcode_handle_hash = barcode_handle_hash.clone();
+                    process_record_single(
+                        record_r1,
+                        &index_records,
+                        ext,
+                        &layouts,
+                        &read_align_opts,
+                        sample_sheet.as_ref(),
+                        prefix,
+                        outdir,
+                        barcode_handle_hash,
+                        template.clone(),
+                        outputs.clone(),
+                    )
+                })
         }
+        check_index_exhausted(&mut index_readers);
     }
 
     // write read flag stat file
@@ -220,6 +400,39 @@ fn main() {
     flag_stat_hash.lock().unwrap().iter().for_each(|(k, v)| {
         write!(flag_stat_handle, "{}\t{}\n", k, v);
     });
+
+    // write read status stat file
+    let status_stat_file = outdir.join(format!("{}.{}", prefix, "read_status.stat.tsv"));
+    let mut status_stat_handle = File::create(status_stat_file).unwrap();
+    status_stat_hash.lock().unwrap().iter().for_each(|(k, v)| {
+        writeln!(status_stat_handle, "{}\t{}", k, v).unwrap();
+    });
+
+    // write the index pair matrix of the first two Index blocks (of the sample sheet if given),
+    // and the index hopping rate of every sample
+    let mut index_names: Vec<String> = match &sample_sheet {
+        Some(sample_sheet) => sample_sheet.index_names.clone(),
+        None => layouts
+            .iter()
+            .flat_map(|x| x.all_block_info())
+            .filter(|x| x.seq_type.is_index())
+            .map(|x| x.idx.to_owned())
+            .collect(),
+    };
+    // the same Index block can be defined in several layouts
+    let mut seen = HashSet::new();
+    index_names.retain(|x| seen.insert(x.to_owned()));
+    if let [index_a, index_b, ..] = &index_names[..] {
+        let counts = index_pair_counts(&index_stat_hash.lock().unwrap(), index_a, index_b);
+        let matrix_file = outdir.join(format!("{}.{}", prefix, "index_pair.matrix.tsv"));
+        let matrix = index_pair_matrix(&counts, sample_sheet.as_ref(), index_a, index_b);
+        fs::write(matrix_file, matrix).unwrap();
+        if let Some(sample_sheet) = &sample_sheet {
+            let hopping_file = outdir.join(format!("{}.{}", prefix, "index_hopping.stat.tsv"));
+            let hopping = index_hopping(&counts, sample_sheet, index_a, index_b);
+            fs::write(hopping_file, hopping).unwrap();
+        }
+    }
     info!("End");
 
 }
\ No newline at end of file
@@ -41,6 +41,16 @@ impl BlockAlign {
         self.align.as_ref().map(|align| align.query_end)
     }
 
+    /// the best hit ties with the second best one, or is less than the min_margin
+    /// of the block ahead of it
+    pub fn is_ambiguous(&self) -> bool {
+        let min_margin = self.info.aligner.as_ref().and_then(|x| x.params().min_margin);
+        let min_margin = min_margin.unwrap_or(0).max(1);
+        self.align
+            .as_ref()
+            .is_some_and(|x| x.n_best > 1 || x.margin().is_some_and(|x| x < min_margin))
+    }
+
     pub fn to_abbr(&self) -> Option<BlockAlignAbbr> {
         // let mut hash = HashMap::new();
         self.align.as_ref().map(|aln| BlockAlignAbbr {
@@ -54,6 +64,7 @@ impl BlockAlign {
             strand: aln.strand.clone(),
             // flag: aln.flag
             flag: self.info.flag,
+            n_best: aln.n_best,
         })
     }
 }
@@ -69,12 +80,13 @@ pub struct BlockAlignAbbr {
     pub n_match: usize,
     pub strand: Strand,
     pub flag: usize,
+    pub n_best: usize,
 }
 
 impl BlockAlignAbbr {
     pub fn to_str(&self) -> String {
         format!(
-            "{}:{}:{}:{}:{}:{}:{}:{:#?}",
+            "{}:{}:{}:{}:{}:{}:{}:{:#?}:{}",
             self.block_name,
             self.best_index,
             self.index_start,
@@ -82,7 +94,8 @@ impl BlockAlignAbbr {
             self.query_start,
             self.query_end,
             self.n_match,
-            self.strand
+            self.strand,
+            self.n_best
         )
     }
 }
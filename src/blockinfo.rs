@@ -1,5 +1,6 @@
 #![allow(unused)]
-use anyhow::Result;
+use anyhow::{bail, Result};
+use bio::alignment::distance;
 use bio::io::fasta;
 use csv::{ReaderBuilder, StringRecord};
 use once_cell::sync::Lazy;
@@ -7,13 +8,15 @@ use rayon::vec;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::hash::Hash;
 use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::aligner::BAligner;
+use crate::aligner::{end_clip_align, AlignParams, Alignment, BAligner};
 use crate::utils::{read_fasta, write_fasta};
 
 pub static BLOCKFLAGS: Lazy<Arc<Mutex<HashMap<usize, String>>>> = Lazy::new(|| {
@@ -29,6 +32,8 @@ pub enum BlockType {
     Anchor,
     Index,
     Variable,
+    /// a variable block holding the unique molecular identifier
+    UMI,
     Other,
 }
 
@@ -39,6 +44,7 @@ impl BlockType {
             "Anchor" => Self::Anchor,
             "Index" => Self::Index,
             "Variable" => Self::Variable,
+            "UMI" => Self::UMI,
             _ => Self::Other,
         }
     }
@@ -49,7 +55,11 @@ impl BlockType {
     }
 
     pub fn is_variable(&self) -> bool {
-        self.clone() == BlockType::Variable
+        self.clone() == BlockType::Variable || self.is_umi()
+    }
+
+    pub fn is_umi(&self) -> bool {
+        self.clone() == BlockType::UMI
     }
 
     pub fn is_index(&self) -> bool {
@@ -68,14 +78,26 @@ pub struct BlockInfo {
     pub seq_len: Option<usize>, // pub aligner: Box
     pub aligner: Option<BAligner>,
     pub flag: usize,
+    /// the max distance to the previous matched block in the ordered search
+    pub max_gap: Option<usize>,
+    /// allow the block sequence to hang off the read start/end
+    pub end_clip: bool,
+    /// the length range of the variable block
+    pub min_len: Option<usize>,
+    pub max_len: Option<usize>,
+    /// the distance of the variable block with seq_len to its neighbouring block
+    pub offset: usize,
+    /// the absent optional block doesn't break its neighbouring variable blocks
+    pub optional: bool,
+    /// the read where the block lies
+    pub read: ReadType,
 }
 
 impl BlockInfo {
-    /// Returns the query slice based on the query_start and query_end
-    fn get_query_seq(self, query: &[u8]) -> (&[u8], usize) {
-        let query_len = query.len();
+    /// Returns the query range based on the query_start and query_end
+    fn get_query_range(&self, query_len: usize) -> (usize, usize) {
         let start = if let Some(start) = self.query_start {
-            start
+            start.min(query_len)
         } else {
             0
         };
@@ -87,7 +109,78 @@ impl BlockInfo {
             query_len
         };
 
-        (&query[start..end.min(query_len)], start)
+        (start, end.clamp(start, query_len))
+    }
+
+    /// align the read only in the search window of the block,
+    /// the coordinates of the alignment are translated back to the read
+    pub fn align(&self, read: &[u8]) -> Option<Alignment> {
+        self.align_range(read, 0, read.len())
+    }
+
+    /// the same as `align`, but the search window is further limited to `[start, end)`
+    pub fn align_range(&self, read: &[u8], start: usize, end: usize) -> Option<Alignment> {
+        let (query_start, query_end) = self.get_query_range(read.len());
+        let start = start.max(query_start);
+        let end = end.min(query_end);
+        if start >= end {
+            return None;
+        }
+        let aligner = self.aligner.as_ref()?;
+        let mut align = aligner.align(&read[start..end]).or_else(|| {
+            if !self.end_clip {
+                return None;
+            }
+            let (at_start, at_end) = (start == 0, end == read.len());
+            end_clip_align(&read[start..end], &self.seqs, aligner.params(), at_start, at_end)
+        })?;
+        align.query_start += start;
+        align.query_end += start;
+        Some(align)
+    }
+
+    /// the same as `align_range` with the low quality bases of the read masked, the unmasked
+    /// read is aligned if the masked one misses, e.g. a HASH window with more masked bases
+    /// than it expands
+    pub fn align_range_masked(
+        &self,
+        read: &[u8],
+        masked: Option<&[u8]>,
+        start: usize,
+        end: usize,
+    ) -> Option<Alignment> {
+        masked
+            .and_then(|x| self.align_range(x, start, end))
+            .or_else(|| self.align_range(read, start, end))
+    }
+
+    /// whether the length of the variable block is in `[min_len, max_len]`,
+    /// and equals to seq_len if set
+    pub fn is_valid_len(&self, len: usize) -> bool {
+        self.min_len.is_none_or(|x| len >= x)
+            && self.max_len.is_none_or(|x| len <= x)
+            && self.seq_len.is_none_or(|x| len == x)
+    }
+
+    /// the length of the longest sequence of the block
+    pub fn max_seq_len(&self) -> usize {
+        self.seqs.values().map(|x| x.len()).max().unwrap_or(0)
+    }
+}
+
+/// the read of the pair or the index reads, R1 by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+pub enum ReadType {
+    #[default]
+    R1,
+    R2,
+    I1,
+    I2,
+}
+
+impl fmt::Display for ReadType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
     }
 }
 
@@ -96,6 +189,10 @@ pub enum AlignMethod {
     #[default]
     SW,
     ANT,
+    /// exact lookup in the precomputed hamming neighbourhood, for barcodes
+    HASH,
+    /// bit-parallel edit distance, for the short sequences (<= 64 bp)
+    MYERS,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -108,6 +205,7 @@ struct BlockInfoFile {
     query_end: Option<usize>,
     seq_len: Option<usize>,
     method: AlignMethod,
+    max_gap: Option<usize>,
 }
 
 /// read block.info.tsv 获取 blockinfo 信息方便后面以 blockinfo 为基础, 对 read 进行比对
@@ -131,6 +229,13 @@ pub fn get_block_info(file_path: &str) -> Vec<BlockInfo> {
                 seq_len: None,
                 aligner: None,
                 flag: 0,
+                max_gap: record.max_gap,
+                end_clip: false,
+                min_len: None,
+                max_len: None,
+                offset: 0,
+                optional: false,
+                read: ReadType::R1,
             };
             block_info_vec.push(bi);
             continue;
@@ -143,7 +248,11 @@ pub fn get_block_info(file_path: &str) -> Vec<BlockInfo> {
 
         let seq_hash =
             read_fasta(&record.fasta_file.unwrap()).expect("read reference fasta file failed");
-        let aligner = BAligner::new(record.method, &seq_hash, record.max_mismatch);
+        let params = AlignParams {
+            max_mismatch: record.max_mismatch,
+            ..Default::default()
+        };
+        let aligner = BAligner::new(record.method, &seq_hash, &params);
         let bi = BlockInfo {
             idx: record.idx.clone(),
             seq_type: BlockType::from_str(&record.seq_type),
@@ -154,6 +263,13 @@ pub fn get_block_info(file_path: &str) -> Vec<BlockInfo> {
             seq_len: record.seq_len,
             aligner: Some(aligner),
             flag,
+            max_gap: record.max_gap,
+            end_clip: false,
+            min_len: None,
+            max_len: None,
+            offset: 0,
+            optional: false,
+            read: ReadType::R1,
         };
         BLOCKFLAGS.lock().unwrap().insert(flag, record.idx);
         flag *= 2;
@@ -174,6 +290,122 @@ struct BlockInfoFileWithoutFasta {
     query_end: Option<usize>,
     seq_len: Option<usize>,
     method: AlignMethod,
+    max_gap: Option<usize>,
+    end_clip: Option<bool>,
+    // the length constraints of the variable block, exact_len sets both
+    min_len: Option<usize>,
+    max_len: Option<usize>,
+    exact_len: Option<usize>,
+    offset: Option<usize>,
+    optional: Option<bool>,
+    read: Option<ReadType>,
+    // the optional alignment parameters, see `AlignParams`
+    max_error_rate: Option<f64>,
+    min_aln_frac: Option<f64>,
+    min_margin: Option<i32>,
+    gap_open: Option<i32>,
+    gap_extend: Option<i32>,
+    match_score: Option<i32>,
+    mismatch_score: Option<i32>,
+    kmer_len: Option<usize>,
+    band_width: Option<usize>,
+    min_seed_len: Option<usize>,
+    multimap_score_range: Option<i32>,
+}
+
+impl BlockInfoFileWithoutFasta {
+    /// the alignment parameters of the block, the missing column uses the default
+    fn align_params(&self) -> AlignParams {
+        let default = AlignParams::default();
+        AlignParams {
+            max_mismatch: self.max_mismatch,
+            max_error_rate: self.max_error_rate,
+            min_aln_frac: self.min_aln_frac,
+            min_margin: self.min_margin,
+            gap_open: self.gap_open.unwrap_or(default.gap_open),
+            gap_extend: self.gap_extend.unwrap_or(default.gap_extend),
+            match_score: self.match_score.unwrap_or(default.match_score),
+            mismatch_score: self.mismatch_score.unwrap_or(default.mismatch_score),
+            kmer_len: self.kmer_len.unwrap_or(default.kmer_len),
+            band_width: self.band_width.unwrap_or(default.band_width),
+            min_seed_len: self.min_seed_len.unwrap_or(default.min_seed_len),
+            multimap_score_range: self
+                .multimap_score_range
+                .unwrap_or(default.multimap_score_range),
+        }
+    }
+}
+
+/// a read structure of the library, several layouts can be mixed in one run
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub name: String,
+    pub block_info_list: Vec<BlockInfo>,
+    /// the blocks on read 2 in the paired end mode
+    pub r2_block_info_list: Vec<BlockInfo>,
+    /// the blocks on the index reads I1/I2
+    pub index_block_info: HashMap<ReadType, Vec<BlockInfo>>,
+}
+
+impl Layout {
+    /// split the blocks by the read they lie on
+    pub fn new(name: &str, block_info_list: Vec<BlockInfo>) -> Self {
+        let mut layout = Layout {
+            name: name.to_string(),
+            block_info_list: vec![],
+            r2_block_info_list: vec![],
+            index_block_info: HashMap::new(),
+        };
+        for block_info in block_info_list {
+            match block_info.read {
+                ReadType::R1 => layout.block_info_list.push(block_info),
+                ReadType::R2 => layout.r2_block_info_list.push(block_info),
+                _ => layout
+                    .index_block_info
+                    .entry(block_info.read.clone())
+                    .or_default()
+                    .push(block_info),
+            }
+        }
+        layout
+    }
+
+    /// the blocks on all the reads
+    pub fn all_block_info(&self) -> impl Iterator<Item = &BlockInfo> {
+        self.block_info_list
+            .iter()
+            .chain(self.r2_block_info_list.iter())
+            .chain(self.index_block_info.values().flatten())
+    }
+}
+
+/// read the layouts from the blockinfo files given as `NAME=FILE` or `FILE`,
+/// the name defaults to the file stem, the close Index barcodes are an error if `strict_index`
+pub fn get_layouts_from_file(
+    blockinfo_files: &[String],
+    fasta_file: &str,
+    strict_index: bool,
+) -> Result<Vec<Layout>> {
+    let fasta_str = std::fs::read_to_string(fasta_file)?;
+    let multi = blockinfo_files.len() > 1;
+    let mut first_flag = 1;
+    let mut layouts = vec![];
+    for spec in blockinfo_files {
+        let (name, blockinfo_file) = match spec.split_once('=') {
+            Some((name, file)) => (name.to_string(), file),
+            None => {
+                let stem = Path::new(spec).file_stem().and_then(|x| x.to_str());
+                (stem.unwrap_or(spec).to_string(), spec.as_str())
+            }
+        };
+        let blockinfo_str = std::fs::read_to_string(blockinfo_file)?;
+        let layout = multi.then_some(name.as_str());
+        let block_info_list =
+            parse_block_info(&blockinfo_str, &fasta_str, layout, first_flag, strict_index)?;
+        first_flag <<= block_info_list.iter().filter(|x| x.flag > 0).count();
+        layouts.push(Layout::new(&name, block_info_list));
+    }
+    Ok(layouts)
 }
 
 /// 从文件读取 blockinfo 信息 及 fasta 信息
@@ -198,12 +430,24 @@ pub fn get_block_info_fasta_from_file(
 
 /// get the block info from string to support the wasm
 pub fn get_block_info_fasta(blockinfo_str: &str, fasta_str: &str) -> Result<Vec<BlockInfo>> {
+    parse_block_info(blockinfo_str, fasta_str, None, 1, false)
+}
+
+/// the block flags start from `first_flag` to keep them unique among the layouts,
+/// the flag names are prefixed by the layout name if set
+fn parse_block_info(
+    blockinfo_str: &str,
+    fasta_str: &str,
+    layout: Option<&str>,
+    first_flag: usize,
+    strict_index: bool,
+) -> Result<Vec<BlockInfo>> {
     let mut rdr = ReaderBuilder::new()
         .delimiter(b'\t')
         .from_reader(blockinfo_str.as_bytes());
     // let mut rdr = csv::ReaderBuilder::new().delimiter(b'\t').from_reader(file);
     let mut block_info_vec: Vec<BlockInfo> = vec![];
-    let mut flag: usize = 1;
+    let mut flag: usize = first_flag;
     // info!(fasta_str);
     let reader = fasta::Reader::new(Cursor::new(fasta_str));
     let fasta_seq: HashMap<String, Vec<u8>> = reader
@@ -224,9 +468,16 @@ pub fn get_block_info_fasta(blockinfo_str: &str, fasta_str: &str) -> Result<Vec<
                 max_mismatch: record.max_mismatch,
                 query_start: record.query_start,
                 query_end: record.query_end,
-                seq_len: None,
+                seq_len: record.seq_len,
                 aligner: None,
                 flag: 0,
+                max_gap: record.max_gap,
+                end_clip: record.end_clip.unwrap_or(false),
+                min_len: record.exact_len.or(record.min_len),
+                max_len: record.exact_len.or(record.max_len),
+                offset: record.offset.unwrap_or(0),
+                optional: record.optional.unwrap_or(false),
+                read: record.read.clone().unwrap_or_default(),
             };
             block_info_vec.push(bi);
             continue;
@@ -252,7 +503,16 @@ pub fn get_block_info_fasta(blockinfo_str: &str, fasta_str: &str) -> Result<Vec<
             })
             .collect();
 
-        let aligner = BAligner::new(record.method, &seqs, record.max_mismatch);
+        if matches!(record.method, AlignMethod::MYERS) {
+            if let Some((name, _)) = seqs.iter().find(|(_, x)| x.len() > 64) {
+                bail!("{}: the sequence {} is longer than 64 bp for MYERS", record.idx, name);
+            }
+        }
+        let params = record.align_params();
+        let aligner = BAligner::new(record.method.clone(), &seqs, &params);
+        if seq_type.is_index() {
+            check_index_distance(&record.idx, &seqs, &aligner, &params, strict_index)?;
+        }
         let bi = BlockInfo {
             idx: record.idx.clone(),
             seq_type: BlockType::from_str(&record.seq_type),
@@ -263,14 +523,104 @@ pub fn get_block_info_fasta(blockinfo_str: &str, fasta_str: &str) -> Result<Vec<
             seq_len: record.seq_len,
             aligner: Some(aligner),
             flag,
+            max_gap: record.max_gap,
+            end_clip: record.end_clip.unwrap_or(false),
+            min_len: None,
+            max_len: None,
+            offset: 0,
+            optional: record.optional.unwrap_or(false),
+            read: record.read.clone().unwrap_or_default(),
         };
-        BLOCKFLAGS.lock().unwrap().insert(flag, record.idx);
+        let flag_name = match layout {
+            Some(layout) => format!("{}:{}", layout, record.idx),
+            None => record.idx,
+        };
+        BLOCKFLAGS.lock().unwrap().insert(flag, flag_name);
         flag *= 2;
         block_info_vec.push(bi);
     }
     Ok(block_info_vec)
 }
 
+/// the closest pair of the block sequences and their distance, Hamming distance of the
+/// same length if `hamming` (HASH only allows substitutions), edit distance otherwise
+fn min_seq_distance(
+    seqs: &HashMap<String, Vec<u8>>,
+    hamming: bool,
+) -> Option<(usize, String, String)> {
+    let mut names: Vec<&String> = seqs.keys().collect();
+    names.sort();
+    let mut closest: Option<(usize, String, String)> = None;
+    for (ii, a) in names.iter().enumerate() {
+        for b in names[ii + 1..].iter() {
+            let (seq_a, seq_b) = (seqs[*a].to_ascii_uppercase(), seqs[*b].to_ascii_uppercase());
+            let dist = if hamming && seq_a.len() == seq_b.len() {
+                distance::hamming(&seq_a, &seq_b) as usize
+            } else {
+                distance::levenshtein(&seq_a, &seq_b) as usize
+            };
+            if closest.as_ref().is_none_or(|x| dist < x.0) {
+                closest = Some((dist, a.to_string(), b.to_string()));
+            }
+        }
+    }
+    closest
+}
+
+/// the max number of sequences to compare pairwise in the Index distance check
+const MAX_PAIRWISE_SEQS: usize = 1000;
+
+/// the barcodes of an Index block should be at least 2*max_mismatch+1 apart,
+/// otherwise a read can be within max_mismatch of two barcodes, warn if so,
+/// or fail if `strict`. The barcodes of the same length of HASH are found in
+/// its neighbourhood table, the others are compared pairwise up to `MAX_PAIRWISE_SEQS`
+fn check_index_distance(
+    idx: &str,
+    seqs: &HashMap<String, Vec<u8>>,
+    aligner: &BAligner,
+    params: &AlignParams,
+    strict: bool,
+) -> Result<()> {
+    let min_len = seqs.values().map(|x| x.len()).min().unwrap_or(0);
+    let max_mismatch = params.max_mismatch_for(min_len);
+    let same_len = seqs.values().all(|x| x.len() == min_len);
+    let closest = match aligner {
+        BAligner::HashAligner(aligner) if same_len => aligner.closest_pair(),
+        _ if seqs.len() > MAX_PAIRWISE_SEQS => {
+            warn!(
+                "{}: {} sequences, more than {}, the distance check is skipped",
+                idx,
+                seqs.len(),
+                MAX_PAIRWISE_SEQS
+            );
+            return Ok(());
+        }
+        _ => min_seq_distance(seqs, matches!(aligner, BAligner::HashAligner(_))),
+    };
+    let Some((dist, a, b)) = closest else {
+        return Ok(());
+    };
+    if dist > 2 * max_mismatch {
+        return Ok(());
+    }
+    // the max max_mismatch keeping the barcodes apart
+    let safe = dist.saturating_sub(1) / 2;
+    let msg = format!(
+        "{}: {} and {} are {} apart, less than 2*max_mismatch+1 ({}), the safe max_mismatch is {}",
+        idx,
+        a,
+        b,
+        dist,
+        2 * max_mismatch + 1,
+        safe
+    );
+    if strict {
+        bail!(msg);
+    }
+    warn!("{}", msg);
+    Ok(())
+}
+
 #[test]
 fn test_get_block_info() {
     let workdir = env::current_dir();
@@ -302,3 +652,88 @@ ATCGATCGTAAAAA";
     let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file);
     dbg!(blockinfo_vec);
 }
+
+#[test]
+fn test_block_align_window() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	query_start	query_end	seq_len	method
+aa	Fix	aa1	0	10			SW
+bb	Fix	aa1	0		12		SW";
+    let fasta_file = ">aa1
+ATCGATCGTA";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read = b"ATCGATCGTATTTTTATCGATCGTA";
+    let align = blockinfo_vec[0].align(read).unwrap();
+    assert_eq!((align.query_start, align.query_end), (15, 25));
+    let align = blockinfo_vec[1].align(read).unwrap();
+    assert_eq!((align.query_start, align.query_end), (0, 10));
+}
+
+#[test]
+fn test_block_align_params() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	mismatch_score	kmer_len
+aa	Fix	aa1	1	SW		
+bb	Fix	aa1	1	SW	-3	2";
+    let mut rdr = ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(blockinfo_str.as_bytes());
+    let records: Vec<BlockInfoFileWithoutFasta> = rdr.deserialize().map(|x| x.unwrap()).collect();
+    let params = records[1].align_params();
+    assert_eq!(params.max_mismatch, 1);
+    assert_eq!((params.mismatch_score, params.kmer_len), (-3, 2));
+    assert_eq!(params.gap_open, AlignParams::default().gap_open);
+    // the mismatch is clipped by the local alignment with the higher penalty
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, ">aa1\nATCGATCGTA").unwrap();
+    assert!(blockinfo_vec[0].align(b"TTATCGATCCTATT").is_some());
+    assert!(blockinfo_vec[1].align(b"TTATCGATCCTATT").is_none());
+}
+
+#[test]
+fn test_block_align_end_clip() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	end_clip
+aa	Fix	aa1	1	SW	true
+bb	Fix	aa1	1	SW	";
+    let fasta_file = ">aa1
+AAACCTGAGAAACCAT";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read = b"GCCGATCGATCGGGAAACCTGAGA";
+    let align = blockinfo_vec[0].align(read).unwrap();
+    assert_eq!((align.query_start, align.query_end), (14, 24));
+    assert!(blockinfo_vec[1].align(read).is_none());
+}
+
+#[test]
+fn test_check_index_distance() {
+    let fasta_str = ">aa
+GTCACGGG
+>bb
+GTCACGTA
+>cc
+GTCATTTA
+";
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Sample	Index	aa,bb,cc	1	HASH";
+    // only warned by default
+    assert!(get_block_info_fasta(blockinfo_str, fasta_str).is_ok());
+    let err = parse_block_info(blockinfo_str, fasta_str, None, 1, true).unwrap_err();
+    assert!(err.to_string().contains("the safe max_mismatch is 0"));
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Sample	Index	aa,cc	1	HASH";
+    assert!(parse_block_info(blockinfo_str, fasta_str, None, 1, true).is_ok());
+    let seqs: HashMap<String, Vec<u8>> =
+        [("aa", "GTCACGGG"), ("cc", "GTCATTTA"), ("dd", "TCACGGG")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect();
+    let closest = min_seq_distance(&seqs, true).unwrap();
+    assert_eq!(closest, (1, "aa".to_string(), "dd".to_string()));
+}
+
+#[test]
+fn test_myers_seq_too_long() {
+    let seq = "ACGT".repeat(17);
+    let fasta_str = format!(">long\n{}\n", seq);
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	long	1	MYERS";
+    let err = get_block_info_fasta(blockinfo_str, &fasta_str).unwrap_err();
+    assert!(err.to_string().contains("Fix_0"));
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::samplesheet::SampleSheet;
+
+/// the reads of every combination of the Index hits (block name: best_index)
+pub type IndexStat = HashMap<BTreeMap<String, String>, usize>;
+
+/// the reads of every combination of the hits of two Index blocks
+pub fn index_pair_counts(
+    index_stat: &IndexStat,
+    index_a: &str,
+    index_b: &str,
+) -> HashMap<(String, String), usize> {
+    let mut counts = HashMap::new();
+    for (hits, n) in index_stat.iter() {
+        if let (Some(a), Some(b)) = (hits.get(index_a), hits.get(index_b)) {
+            *counts.entry((a.to_owned(), b.to_owned())).or_insert(0) += n;
+        }
+    }
+    counts
+}
+
+/// the expected pairs of the two Index blocks in the sample sheet: sample
+fn expected_pairs(
+    sample_sheet: &SampleSheet,
+    index_a: &str,
+    index_b: &str,
+) -> HashMap<(String, String), String> {
+    let pos = |name: &str| sample_sheet.index_names.iter().position(|x| x == name);
+    let (Some(pos_a), Some(pos_b)) = (pos(index_a), pos(index_b)) else {
+        return HashMap::new();
+    };
+    sample_sheet
+        .samples
+        .iter()
+        .map(|(key, sample)| {
+            let pair = (key[pos_a].to_owned(), key[pos_b].to_owned());
+            (pair, sample.name.to_owned())
+        })
+        .collect()
+}
+
+/// the matrix of the combinations, index_a by rows and index_b by columns,
+/// the expected pairs of the sample sheet are marked with `*`
+pub fn index_pair_matrix(
+    counts: &HashMap<(String, String), usize>,
+    sample_sheet: Option<&SampleSheet>,
+    index_a: &str,
+    index_b: &str,
+) -> String {
+    let expected = sample_sheet
+        .map(|x| expected_pairs(x, index_a, index_b))
+        .unwrap_or_default();
+    let pairs = counts.keys().chain(expected.keys());
+    let (rows, cols): (BTreeSet<&String>, BTreeSet<&String>) =
+        pairs.map(|(a, b)| (a, b)).unzip();
+    let mut matrix = format!("{}/{}", index_a, index_b);
+    cols.iter().for_each(|b| matrix.push_str(&format!("\t{}", b)));
+    for a in rows.iter() {
+        matrix.push_str(&format!("\n{}", a));
+        for b in cols.iter() {
+            let pair = (a.to_string(), b.to_string());
+            let n = counts.get(&pair).unwrap_or(&0);
+            let mark = if expected.contains_key(&pair) { "*" } else { "" };
+            matrix.push_str(&format!("\t{}{}", n, mark));
+        }
+    }
+    matrix.push('\n');
+    matrix
+}
+
+/// the index hopping rate of every sample, the reads sharing only one index with
+/// the sample in an unexpected combination are taken as hopped out of the sample
+pub fn index_hopping(
+    counts: &HashMap<(String, String), usize>,
+    sample_sheet: &SampleSheet,
+    index_a: &str,
+    index_b: &str,
+) -> String {
+    let expected = expected_pairs(sample_sheet, index_a, index_b);
+    let expected_set: HashSet<&(String, String)> = expected.keys().collect();
+    let mut samples: Vec<(&String, &(String, String))> =
+        expected.iter().map(|(pair, sample)| (sample, pair)).collect();
+    samples.sort();
+    let mut hopping = format!(
+        "sample\t{}\t{}\treads\thopped_reads\thopping_rate\n",
+        index_a, index_b
+    );
+    for (sample, (a, b)) in samples {
+        let reads = *counts.get(&(a.to_owned(), b.to_owned())).unwrap_or(&0);
+        let hopped: usize = counts
+            .iter()
+            .filter(|(pair, _)| !expected_set.contains(pair))
+            .filter(|((x, y), _)| (x == a) != (y == b))
+            .map(|(_, n)| n)
+            .sum();
+        let rate = if reads + hopped > 0 {
+            hopped as f64 / (reads + hopped) as f64
+        } else {
+            0.0
+        };
+        hopping.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\n",
+            sample, a, b, reads, hopped, rate
+        ));
+    }
+    hopping
+}
+
+#[test]
+fn test_index_hopping() {
+    let sheet_str = "sample	i7	i5
+s1	A1	B1
+s2	A2	B2";
+    let index_names = vec!["i7".to_string(), "i5".to_string()];
+    let sheet = SampleSheet::from_tsv(sheet_str, &index_names).unwrap();
+    let mut index_stat = IndexStat::new();
+    for (a, b, n) in [("A1", "B1", 90), ("A1", "B2", 5), ("A2", "B2", 45), ("A2", "B1", 5)] {
+        let hits = [("i7", a), ("i5", b)]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        index_stat.insert(hits, n);
+    }
+    let counts = index_pair_counts(&index_stat, "i7", "i5");
+    let matrix = index_pair_matrix(&counts, Some(&sheet), "i7", "i5");
+    assert_eq!(matrix, "i7/i5\tB1\tB2\nA1\t90*\t5\nA2\t5\t45*\n");
+    let hopping = index_hopping(&counts, &sheet, "i7", "i5");
+    let lines: Vec<&str> = hopping.lines().collect();
+    assert_eq!(lines[1], "s1\tA1\tB1\t90\t10\t0.1000");
+    assert_eq!(lines[2], "s2\tA2\tB2\t45\t10\t0.1818");
+}
@@ -1,10 +1,12 @@
 pub mod aligner;
 pub mod blockalign;
 pub mod blockinfo;
+pub mod indexstat;
 // pub mod output;
 pub mod utils;
 // pub mod wapper;
 pub mod readblockalign;
+pub mod samplesheet;
 pub mod wasm;
 
 pub mod record;
@@ -2,6 +2,8 @@
 #![allow(dead_code)]
 #![allow(unused)]
 use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::fmt;
 use bio::io::fastq;
 use minijinja::value::Value;
 use minijinja::Template;
@@ -12,8 +14,104 @@ use crate::blockinfo::get_block_info_fasta;
 // use crate::blockinfo::get_block_info_fasta;
 use crate::record::Record;
 use crate::utils::{Strand, revcomp};
-use crate::utils::{check_vec_equal, dna_to_spans};
-use crate::{blockalign::BlockAlign, blockinfo::BlockInfo};
+use crate::utils::{dna_to_spans, mask_low_qual, HIDDEN_BASE};
+use crate::blockinfo::{BlockInfo, Layout, ReadType};
+use crate::blockalign::BlockAlign;
+use crate::samplesheet::{Sample, SampleSheet};
+
+/// the max number of segments split from a concatemer read
+const MAX_SEGMENTS: usize = 100;
+
+/// options of the block alignment shared by all the reads
+#[derive(Debug, Clone, Default)]
+pub struct ReadAlignOpts {
+    /// search every fix block only downstream of the previous matched block
+    pub ordered: bool,
+    /// the read bases below this phred quality match any base of the fix blocks
+    pub min_qual: Option<u8>,
+    /// the min number of fix blocks agreeing with the voted strand
+    pub min_strand_anchors: usize,
+    /// split the concatemer read by every occurrence of the block layout
+    pub segment: bool,
+    /// merge the overlapping pair before the block alignment
+    pub merge: bool,
+    /// swap the read 1 and read 2 if the layout matches the read 2 better
+    pub auto_swap: bool,
+    /// the min overlap of the merged pair
+    pub min_overlap: usize,
+    /// where to write the UMI blocks in the read header
+    pub umi: Option<UmiMode>,
+}
+
+/// how the UMI is written in the read header
+#[derive(Debug, Clone, PartialEq)]
+pub enum UmiMode {
+    /// appended to the read name, e.g. `@read000_ACGTACGTAC`
+    Name,
+    /// the RX:Z (sequence) and QX:Z (quality) tags of the comment
+    Tag,
+}
+
+impl std::str::FromStr for UmiMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(UmiMode::Name),
+            "tag" => Ok(UmiMode::Tag),
+            _ => Err(format!("unknown UMI mode {}, name or tag", s)),
+        }
+    }
+}
+
+/// the record with the UMI (sequence, quality) in its header
+pub fn annotate_umi<R: Record>(record: &R, umi: &(String, String), umi_mode: &UmiMode) -> R {
+    let (seq, qual) = umi;
+    match umi_mode {
+        UmiMode::Name => record.with_name(&format!("{}_{}", record.id(), seq), record.desc()),
+        UmiMode::Tag => {
+            let tags = format!("RX:Z:{}\tQX:Z:{}", seq, qual);
+            let desc = match record.desc() {
+                Some(desc) => format!("{}\t{}", desc, tags),
+                None => tags,
+            };
+            record.with_name(record.id(), Some(&desc))
+        }
+    }
+}
+
+/// the reason why a read is (not) resolved
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ReadStatus {
+    #[default]
+    Pass,
+    /// a fix block is only found upstream of the previous matched block
+    OutOfOrder,
+    /// a variable block is out of its length range, the read goes to undetermined
+    BadLength,
+    /// a block not marked as optional is not found, the read goes to undetermined
+    MissingBlock,
+    /// the combination of the Index hits is not in the sample sheet
+    UnexpectedCombination,
+    /// some Index blocks of the sample sheet are not found
+    IncompleteIndex,
+    /// an Index block hits two sequences within its min_margin
+    AmbiguousIndex,
+}
+
+impl fmt::Display for ReadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadStatus::Pass => write!(f, "pass"),
+            ReadStatus::OutOfOrder => write!(f, "out_of_order"),
+            ReadStatus::BadLength => write!(f, "bad_length"),
+            ReadStatus::MissingBlock => write!(f, "missing_block"),
+            ReadStatus::UnexpectedCombination => write!(f, "unexpected_combination"),
+            ReadStatus::IncompleteIndex => write!(f, "incomplete_index"),
+            ReadStatus::AmbiguousIndex => write!(f, "ambiguous_index"),
+        }
+    }
+}
 
 /// save the fastq record and its block align information
 #[derive(Debug, Clone)]
@@ -22,6 +120,17 @@ pub struct ReadBlockAlign<R: Record + Clone> {
     pub record: R,
     pub block_align: HashMap<String, Option<BlockAlign>>, // blockname: block_align
     strand: Strand,
+    pub status: ReadStatus,
+    /// the extra information of the read in the read_info file, key=value
+    pub notes: Vec<String>,
+    /// the name of the chosen layout if several layouts are given
+    pub layout: Option<String>,
+    /// the alignment of the blocks on read 2
+    pub mate: Option<Box<ReadBlockAlign<R>>>,
+    /// the alignment of the blocks on the index reads I1/I2
+    pub index_reads: Vec<(ReadType, ReadBlockAlign<R>)>,
+    /// the sample assigned by the sample sheet
+    pub sample: Option<Sample>,
 }
 
 /// read sequence mapping aganist the block sequence
@@ -33,7 +142,7 @@ pub fn _block_align_read(read: &[u8], block_info_list: &[BlockInfo]) -> Vec<Opti
         let block_info = block_info.to_owned();
         // block_info.query_start = Some(pre_query_end.saturating_sub(OFFSET));
         // let ba = block_info.clone().align(read, aligner);
-        let align = block_info.aligner.clone().unwrap().align(read);
+        let align = block_info.align(read);
         let ba = if let Some(align) = align {
             let ba = BlockAlign {
                 info: block_info.clone(),
@@ -65,6 +174,12 @@ where
             record: record.clone(),
             block_align: block_align.clone(),
             strand,
+            status: ReadStatus::Pass,
+            notes: vec![],
+            layout: None,
+            mate: None,
+            index_reads: vec![],
+            sample: None,
         }
     }
 
@@ -91,122 +206,113 @@ where
         //         // best_index = None;
         //     }
         // });
+        if let Some(mate) = &self.mate {
+            best_index_vec.extend(mate.get_best_index());
+        }
+        for (_, index_read) in self.index_reads.iter() {
+            best_index_vec.extend(index_read.get_best_index());
+        }
 
         best_index_vec
     }
 
     /// core function
     /// get the alignment information of every block inlcluding Fix and Variable
-    pub fn read_block_info(record: &R, block_info_list: &[BlockInfo]) -> Self {
+    pub fn read_block_info(record: &R, block_info_list: &[BlockInfo], opts: &ReadAlignOpts) -> Self {
         let read = record.seq();
         let mut block_align_hash: HashMap<String, Option<BlockAlign>> = HashMap::new();
         let read_len = read.len();
+        let mut status = ReadStatus::Pass;
+        // the fix blocks are aligned to the read with the low quality bases masked
+        let masked_read = opts
+            .min_qual
+            .map(|min_qual| mask_low_qual(read, &record.qual(), min_qual));
+        let masked = masked_read.as_deref();
 
-        let mut strand_vec: Vec<Strand> = vec![];
+        // the strand and the position of the previous matched block in the ordered search
+        let mut pre_align: Option<Alignment> = None;
         // 处理 anchor/fix 序列
         for block_info in block_info_list.iter().filter(|x| x.seq_type.is_fix()) {
             let block_info = block_info.clone();
             let idx = block_info.idx.clone();
-            let align: Option<Alignment> = block_info.aligner.clone().and_then(|x| x.align(read));
-            let ba = align.map(|x| BlockAlign::new(&block_info, &x));
-            block_align_hash.insert(idx, ba.clone());
-            if let Some(ba) = ba {
-                strand_vec.push(ba.get_query_strand().unwrap());
+            let align: Option<Alignment> = if opts.ordered {
+                let align = Self::align_downstream(read, masked, &block_info, pre_align.as_ref());
+                if align.is_some() {
+                    pre_align = align.clone();
+                } else if let Some(pre) = pre_align.as_ref() {
+                    if Self::align_upstream(read, masked, &block_info, pre).is_some() {
+                        status = ReadStatus::OutOfOrder;
+                    }
+                }
+                align
             } else {
-                strand_vec.push(Strand::Ambiguous);
-            }
+                block_info.align_range_masked(read, masked, 0, read_len)
+            };
+            let ba = align.map(|x| BlockAlign::new(&block_info, &x));
+            block_align_hash.insert(idx, ba);
         }
         // 判断正负链
-        let strand_vec_equal = check_vec_equal(&strand_vec);
-        let strand = if strand_vec_equal {
-            strand_vec.first().unwrap_or(&Strand::Ambiguous)
-        } else {
-            &Strand::Ambiguous
-        };
+        let strand = &Self::vote_strand(&block_align_hash, opts.min_strand_anchors);
+        // the anchors disagreeing with the strand are dropped and reported
+        let mut notes = vec![];
+        if *strand != Strand::Ambiguous {
+            let discordant: Vec<String> = block_info_list
+                .iter()
+                .filter(|x| {
+                    block_align_hash
+                        .get(&x.idx)
+                        .and_then(|ba| ba.as_ref())
+                        .and_then(|ba| ba.get_query_strand())
+                        .is_some_and(|x| x != *strand)
+                })
+                .map(|x| x.idx.to_owned())
+                .collect();
+            if !discordant.is_empty() {
+                discordant.iter().for_each(|idx| {
+                    block_align_hash.insert(idx.to_owned(), None);
+                });
+                notes.push(format!("discordant={}", discordant.join(",")));
+            }
+        }
 
         // 处理可变序列
-        let block_info_len = block_info_list.len();
-        for block_ii in 0..block_info_len {
-            let block_info = block_info_list[block_ii].clone();
+        // the fixed-length blocks go first, they can be the neighbours of the open-ended ones
+        let mut bad_length = vec![];
+        let mut variable_ii: Vec<usize> = (0..block_info_list.len())
+            .filter(|&ii| block_info_list[ii].seq_type.is_variable())
+            .collect();
+        variable_ii.sort_by_key(|&ii| block_info_list[ii].seq_len.is_none());
+        for block_ii in variable_ii {
+            let block_info = &block_info_list[block_ii];
             let idx = block_info.idx.to_string();
-            if !block_info.seq_type.is_variable() {
+            let span =
+                Self::variable_span(block_info_list, block_ii, &block_align_hash, strand, read_len);
+            let Some((start, end)) = span else {
+                block_align_hash.insert(idx, None);
                 continue;
-            }
-
-            let mut query_start = None;
-            let mut query_end = None;
-            // 开头模块
-            if block_ii == 0 {
-                match strand {
-                    Strand::Plus => query_start = Some(0),
-                    Strand::Minus => query_end = Some(read_len),
-                    Strand::Ambiguous => {
-                        block_align_hash.insert(idx, None);
-                        continue;
-                    }
-                }
-            } else {
-                let pre_block_info = &block_info_list[block_ii - 1];
-                let ba = block_align_hash.get(&pre_block_info.idx).unwrap();
-
-                if let Some(ba) = ba {
-                    match strand {
-                        Strand::Plus => {
-                            query_start = ba.get_query_end();
-                        }
-                        Strand::Minus => {
-                            query_end = ba.get_query_start();
-                        }
-                        Strand::Ambiguous => {
-                            block_align_hash.insert(idx, None);
-                            continue;
-                        }
-                    }
-                    // if strand.is_reverse().unwrap() {
-                    //     query_end = ba.get_query_start();
-                    // } else {
-                    //     query_start = ba.get_query_end();
-                    // }
-                } else {
-                    block_align_hash.insert(idx, None);
-                    continue;
-                }
-            }
-
-            // 末尾模块
-            if block_ii == (block_info_len - 1) {
-                // query_end = Some(read_len);
-                query_end = if strand.is_reverse().unwrap() {
-                    Some(0)
-                } else {
-                    Some(read_len)
-                };
+            };
+            // the negative span means the neighbouring blocks overlap, which is a bad length
+            // only for the block with a length range, otherwise the block is empty
+            let bad = if start > end {
+                block_info.min_len.is_some() || block_info.max_len.is_some()
             } else {
-                let next_block_info = &block_info_list[block_ii + 1];
-                let ba = block_align_hash.get(&next_block_info.idx).unwrap().clone();
-
-                if let Some(ba) = ba {
-                    // query_start = ba.get_query_end().map(|pos| pos + 1);
-                    if strand.is_reverse().unwrap() {
-                        query_start = ba.get_query_end();
-                    } else {
-                        query_end = ba.get_query_start();
-                    }
-                } else {
-                    block_align_hash.insert(idx, None);
-                    continue;
-                }
+                !block_info.is_valid_len(end - start)
+            };
+            if bad {
+                bad_length.push(format!("{}:{}", idx, end as isize - start as isize));
             }
             let align = Alignment {
                 best_index: "".to_string(),
                 index_start: 0,
                 index_end: 0,
-                query_start: query_start.unwrap(),
-                query_end: query_end.unwrap(),
+                query_start: start,
+                query_end: end,
                 n_match: 0,
                 strand: strand.clone(),
                 operations: None,
-                // operations: vec![AlignmentOperation::]
+                score: 0,
+                n_best: 0,
+                second_score: None,
             };
 
             let ba = BlockAlign {
@@ -219,6 +325,39 @@ where
             block_align_hash.insert(idx, Some(ba));
         }
 
+        if !bad_length.is_empty() {
+            status = ReadStatus::BadLength;
+            notes.push(format!("bad_length={}", bad_length.join(",")));
+        }
+
+        // only the optional blocks may be absent from a rendered read
+        let missing: Vec<&str> = block_info_list
+            .iter()
+            .filter(|x| !x.optional && block_align_hash.get(&x.idx).is_none_or(Option::is_none))
+            .map(|x| x.idx.as_str())
+            .collect();
+        if !missing.is_empty() {
+            if status == ReadStatus::Pass {
+                status = ReadStatus::MissingBlock;
+            }
+            notes.push(format!("missing={}", missing.join(",")));
+        }
+
+        // the Index blocks hitting the second best sequence nearly as well as the best one
+        let mut ambiguous: Vec<String> = block_align_hash
+            .values()
+            .flatten()
+            .filter(|ba| ba.info.seq_type.is_index() && ba.is_ambiguous())
+            .map(|ba| ba.info.idx.to_owned())
+            .collect();
+        if !ambiguous.is_empty() {
+            ambiguous.sort();
+            if status == ReadStatus::Pass {
+                status = ReadStatus::AmbiguousIndex;
+            }
+            notes.push(format!("ambiguous={}", ambiguous.join(",")));
+        }
+
         let block_idx_list: Vec<String> =
             block_info_list.iter().map(|x| x.idx.to_string()).collect();
 
@@ -227,9 +366,437 @@ where
             block_align: block_align_hash,
             block_idx_list,
             strand: strand.clone(),
+            status,
+            notes,
+            layout: None,
+            mate: None,
+            index_reads: vec![],
+            sample: None,
+        }
+    }
+
+    /// align the read with every layout and keep the best ranked one
+    pub fn read_layouts(record: &R, layouts: &[Layout], opts: &ReadAlignOpts) -> Self {
+        if layouts.len() == 1 {
+            return Self::read_block_info(record, &layouts[0].block_info_list, opts);
+        }
+        let (layout, mut best) = layouts
+            .iter()
+            .map(|layout| {
+                let rba = Self::read_block_info(record, &layout.block_info_list, opts);
+                (layout, rba)
+            })
+            // the first layout wins the tie
+            .rev()
+            .max_by_key(|(layout, rba)| rba.rank(layout))
+            .expect("no layout is given");
+        best.layout = Some(layout.name.to_owned());
+        best.notes.push(format!("layout={}", layout.name));
+        best
+    }
+
+    /// the rank of the read aligned with the layout, including the read 2 blocks:
+    /// the resolved read first, then the less absent required blocks, then the more matched bases
+    fn rank(&self, layout: &Layout) -> (bool, isize, usize) {
+        let mut resolved = self.strand != Strand::Ambiguous && self.status == ReadStatus::Pass;
+        let mut n_absent = 0;
+        let mut n_match = 0;
+        let mates = [
+            (Some(self), &layout.block_info_list),
+            (self.mate.as_deref(), &layout.r2_block_info_list),
+        ];
+        for (rba, block_info_list) in mates {
+            let Some(rba) = rba else {
+                continue;
+            };
+            resolved &= rba.strand != Strand::Ambiguous;
+            n_absent += block_info_list
+                .iter()
+                // the blocks of an unknown seq_type are never aligned, they count as absent
+                .filter(|x| {
+                    !x.optional && rba.block_align.get(&x.idx).is_none_or(Option::is_none)
+                })
+                .count();
+            n_match += rba.block_align.values().flatten().map(|x| x.n_match).sum::<usize>();
+        }
+        (resolved, -(n_absent as isize), n_match)
+    }
+
+    /// align the pair and the swapped pair, keep the swapped one if it ranks higher,
+    /// noted as `swapped` in the read_info; returns whether the pair is swapped
+    pub fn read_pair_auto_swap(
+        record_r1: &R,
+        record_r2: &R,
+        layouts: &[Layout],
+        opts: &ReadAlignOpts,
+    ) -> (Self, bool) {
+        let read_block_align = Self::read_pair_layouts(record_r1, record_r2, layouts, opts);
+        let mut swapped = Self::read_pair_layouts(record_r2, record_r1, layouts, opts);
+        let rank = read_block_align.rank(read_block_align.chosen_layout(layouts));
+        if swapped.rank(swapped.chosen_layout(layouts)) > rank {
+            swapped.notes.push("swapped".to_string());
+            (swapped, true)
+        } else {
+            (read_block_align, false)
         }
     }
 
+    /// the layout chosen for the read, the only one if not set
+    fn chosen_layout<'a>(&self, layouts: &'a [Layout]) -> &'a Layout {
+        layouts
+            .iter()
+            .find(|x| self.layout.as_ref() == Some(&x.name))
+            .unwrap_or(&layouts[0])
+    }
+
+    /// align the read 1 with the layouts, then the blocks on read 2 of the chosen layout,
+    /// whose status and notes (prefixed by `r2.`) are merged into the read 1
+    pub fn read_pair_layouts(
+        record_r1: &R,
+        record_r2: &R,
+        layouts: &[Layout],
+        opts: &ReadAlignOpts,
+    ) -> Self {
+        let mut read_block_align = Self::read_layouts(record_r1, layouts, opts);
+        let r2_block_info_list = &read_block_align.chosen_layout(layouts).r2_block_info_list;
+        if r2_block_info_list.is_empty() {
+            return read_block_align;
+        }
+        let mate = Self::read_block_info(record_r2, r2_block_info_list, opts);
+        if read_block_align.status == ReadStatus::Pass {
+            read_block_align.status = mate.status.clone();
+        }
+        let notes = mate.notes.iter().map(|x| format!("r2.{}", x));
+        read_block_align.notes.extend(notes);
+        read_block_align.mate = Some(Box::new(mate));
+        read_block_align
+    }
+
+    /// the best_index of every found Index block, including read 2 and the index reads
+    pub fn get_index_hits(&self) -> HashMap<String, String> {
+        let mates = self.mate.iter().map(|x| x.as_ref());
+        let mates = mates.chain(self.index_reads.iter().map(|x| &x.1));
+        let mut hits = HashMap::new();
+        for rba in std::iter::once(self).chain(mates) {
+            rba.block_align.values().flatten().for_each(|ba| {
+                if ba.info.seq_type.is_index() {
+                    hits.insert(ba.info.idx.to_owned(), ba.best_index.to_owned());
+                }
+            });
+        }
+        hits
+    }
+
+    /// the sequence and quality of the UMI blocks in the block order, including read 2
+    /// and the index reads, the UMIs on the reverse strand are reverse complemented,
+    /// several UMIs are joined by `-` (and the qualities by a space)
+    pub fn get_umi(&self) -> Option<(String, String)> {
+        let mates = self.mate.iter().map(|x| x.as_ref());
+        let mates = mates.chain(self.index_reads.iter().map(|x| &x.1));
+        let mut umis = vec![];
+        for rba in std::iter::once(self).chain(mates) {
+            let seq_len = rba.record.seq().len();
+            for idx in rba.block_idx_list.iter() {
+                let Some(Some(ba)) = rba.block_align.get(idx) else {
+                    continue;
+                };
+                let (Some(start), Some(end)) = (ba.get_query_start(), ba.get_query_end()) else {
+                    continue;
+                };
+                if !ba.info.seq_type.is_umi() || start >= end {
+                    continue;
+                }
+                let (start, end) = (start.min(seq_len), end.min(seq_len));
+                let mut seq = rba.record.seq()[start..end].to_vec();
+                let mut qual = rba.record.qual()[start..end].to_vec();
+                if ba.get_query_strand() == Some(Strand::Minus) {
+                    seq = revcomp(&seq);
+                    qual.reverse();
+                }
+                umis.push((seq, qual));
+            }
+        }
+        if umis.is_empty() {
+            return None;
+        }
+        let (seqs, quals): (Vec<String>, Vec<String>) = umis
+            .into_iter()
+            .map(|(seq, qual)| {
+                let seq = String::from_utf8_lossy(&seq).to_string();
+                (seq, String::from_utf8_lossy(&qual).to_string())
+            })
+            .unzip();
+        Some((seqs.join("-"), quals.join(" ")))
+    }
+
+    /// write the UMI in the header of the read and its mate, return the UMI
+    pub fn annotate_umi(&mut self, umi_mode: &UmiMode) -> Option<(String, String)> {
+        let umi = self.get_umi()?;
+        self.record = annotate_umi(&self.record, &umi, umi_mode);
+        if let Some(mate) = self.mate.as_mut() {
+            mate.record = annotate_umi(&mate.record, &umi, umi_mode);
+        }
+        Some(umi)
+    }
+
+    /// assign the sample of the Index hits, the combination not in the sample sheet
+    /// gets the unexpected_combination status, and the missing Index blocks
+    /// get the incomplete_index status
+    pub fn assign_sample(&mut self, sample_sheet: &SampleSheet) {
+        if self.status == ReadStatus::AmbiguousIndex {
+            return;
+        }
+        let hits = self.get_index_hits();
+        self.sample = sample_sheet.find(&hits).cloned();
+        if self.sample.is_none() && self.status == ReadStatus::Pass {
+            self.status = if sample_sheet.is_complete(&hits) {
+                ReadStatus::UnexpectedCombination
+            } else {
+                ReadStatus::IncompleteIndex
+            };
+        }
+    }
+
+    /// align the blocks on the index reads (I1/I2) of the chosen layout,
+    /// whose status and notes (prefixed by the read name) are merged into the read 1
+    pub fn align_index_reads(
+        &mut self,
+        index_records: &[(ReadType, R)],
+        layouts: &[Layout],
+        opts: &ReadAlignOpts,
+    ) {
+        let layout = self.chosen_layout(layouts);
+        for (read_type, record) in index_records {
+            let block_info_list = layout
+                .index_block_info
+                .get(read_type)
+                .map_or(&[][..], |x| x.as_slice());
+            let index_read = Self::read_block_info(record, block_info_list, opts);
+            if !block_info_list.is_empty() && self.status == ReadStatus::Pass {
+                self.status = index_read.status.clone();
+            }
+            let notes = index_read.notes.iter().map(|x| format!("{}.{}", read_type, x));
+            self.notes.extend(notes);
+            self.index_reads.push((read_type.clone(), index_read));
+        }
+    }
+
+    /// the strand supported by the most matched bases of the fix blocks,
+    /// ambiguous if less than `min_anchors` (at least 1) blocks agree with it
+    fn vote_strand(
+        block_align_hash: &HashMap<String, Option<BlockAlign>>,
+        min_anchors: usize,
+    ) -> Strand {
+        // (number of blocks, number of matched bases)
+        let mut plus = (0, 0);
+        let mut minus = (0, 0);
+        block_align_hash.values().flatten().for_each(|ba| {
+            match ba.get_query_strand() {
+                Some(Strand::Plus) => plus = (plus.0 + 1, plus.1 + ba.n_match),
+                Some(Strand::Minus) => minus = (minus.0 + 1, minus.1 + ba.n_match),
+                _ => {}
+            }
+        });
+        let (strand, n_anchor) = match plus.1.cmp(&minus.1) {
+            Ordering::Greater => (Strand::Plus, plus.0),
+            Ordering::Less => (Strand::Minus, minus.0),
+            Ordering::Equal => return Strand::Ambiguous,
+        };
+        if n_anchor < min_anchors.max(1) {
+            Strand::Ambiguous
+        } else {
+            strand
+        }
+    }
+
+    /// the read range of the variable block between its matched neighbouring blocks,
+    /// the block with seq_len only needs one neighbour and starts `offset` away from it,
+    /// the absent optional blocks are skipped
+    fn variable_span(
+        block_info_list: &[BlockInfo],
+        block_ii: usize,
+        block_align_hash: &HashMap<String, Option<BlockAlign>>,
+        strand: &Strand,
+        read_len: usize,
+    ) -> Option<(usize, usize)> {
+        if *strand == Strand::Ambiguous {
+            return None;
+        }
+        let block_info = &block_info_list[block_ii];
+        // (query_start, query_end) of the matched neighbouring block,
+        // Some(None) if only the absent optional blocks are left to the layout end
+        let neighbour = |forward: bool| -> Option<Option<(usize, usize)>> {
+            let mut ii = block_ii;
+            loop {
+                if (forward && ii + 1 == block_info_list.len()) || (!forward && ii == 0) {
+                    return Some(None);
+                }
+                ii = if forward { ii + 1 } else { ii - 1 };
+                let info = &block_info_list[ii];
+                match block_align_hash.get(&info.idx).and_then(|x| x.as_ref()) {
+                    Some(ba) => return Some(Some((ba.get_query_start()?, ba.get_query_end()?))),
+                    None if info.optional => continue,
+                    None => return None,
+                }
+            }
+        };
+        let pre = neighbour(false);
+        let next = neighbour(true);
+        let is_reverse = *strand == Strand::Minus;
+
+        if let Some(len) = block_info.seq_len {
+            let (pre, next) = (pre.flatten(), next.flatten());
+            let offset = block_info.offset;
+            // downstream of the previous block, or upstream of the next block in the layout
+            let (start, end) = match (pre, next, is_reverse) {
+                (Some(pre), _, false) => (pre.1 + offset, pre.1 + offset + len),
+                (None, Some(next), true) => (next.1 + offset, next.1 + offset + len),
+                (Some((pos, _)), _, true) | (None, Some((pos, _)), false) => (
+                    pos.saturating_sub(offset + len),
+                    pos.saturating_sub(offset),
+                ),
+                _ => return None,
+            };
+            return Some((start.min(read_len), end.min(read_len)));
+        }
+
+        // 开头模块/末尾模块 extend to the read ends
+        let (pre, next) = (pre?, next?);
+        if is_reverse {
+            Some((next.map_or(0, |x| x.1), pre.map_or(read_len, |x| x.0)))
+        } else {
+            Some((pre.map_or(0, |x| x.1), next.map_or(read_len, |x| x.0)))
+        }
+    }
+
+    /// split the concatemer read into segments by every occurrence of the fix blocks
+    /// of the layout chosen for the whole read,
+    /// the segments named read_name/1, read_name/2... are aligned independently
+    pub fn segment_read(record: &R, layouts: &[Layout], opts: &ReadAlignOpts) -> Vec<Self> {
+        let read = record.seq();
+        let qual = record.qual();
+        let whole = Self::read_layouts(record, layouts, opts);
+        let block_info_list = &whole.chosen_layout(layouts).block_info_list;
+        // the blocks of an occurrence are searched in order to keep them together
+        let search_opts = ReadAlignOpts {
+            ordered: true,
+            ..opts.clone()
+        };
+        // (start, end, strand) of every occurrence, the found one is masked before the next search
+        let mut occurrences: Vec<(usize, usize, Strand)> = vec![];
+        let mut masked = read.to_vec();
+        while occurrences.len() < MAX_SEGMENTS {
+            let masked_record = record.with_seq(record.id(), &masked, &qual);
+            let rba = Self::read_block_info(&masked_record, block_info_list, &search_opts);
+            let spans: Vec<(usize, usize)> = rba
+                .block_align
+                .values()
+                .flatten()
+                .filter(|x| x.info.seq_type.is_fix())
+                .filter_map(|x| x.align.as_ref().map(|x| (x.query_start, x.query_end)))
+                .collect();
+            let start = spans.iter().map(|x| x.0).min().unwrap_or(0);
+            let end = spans.iter().map(|x| x.1).max().unwrap_or(0);
+            if rba.strand == Strand::Ambiguous || start >= end {
+                break;
+            }
+            masked[start..end].fill(HIDDEN_BASE);
+            occurrences.push((start, end, rba.strand));
+        }
+        if occurrences.len() < 2 {
+            return vec![whole];
+        }
+
+        // the gap between two occurrences goes to the one whose open-ended variable block
+        // faces it, or is split in the middle
+        occurrences.sort_by_key(|x| x.0);
+        let leading = block_info_list.first().is_some_and(|x| x.seq_type.is_variable());
+        let trailing = block_info_list.last().is_some_and(|x| x.seq_type.is_variable());
+        let mut cuts = vec![0];
+        for pair in occurrences.windows(2) {
+            let (_, pre_end, pre_strand) = &pair[0];
+            let (next_start, _, next_strand) = &pair[1];
+            let gap_start = (*pre_end).max(*cuts.last().unwrap());
+            let gap_end = (*next_start).max(gap_start);
+            let pre_claim = (*pre_strand == Strand::Plus && trailing)
+                || (*pre_strand == Strand::Minus && leading);
+            let next_claim = (*next_strand == Strand::Plus && leading)
+                || (*next_strand == Strand::Minus && trailing);
+            let cut = match (pre_claim, next_claim) {
+                (true, false) => gap_end,
+                (false, true) => gap_start,
+                _ => (gap_start + gap_end) / 2,
+            };
+            cuts.push(cut);
+        }
+        cuts.push(read.len());
+
+        cuts.windows(2)
+            .enumerate()
+            .map(|(ii, x)| {
+                let id = format!("{}/{}", record.id(), ii + 1);
+                let segment = record.with_seq(&id, &read[x[0]..x[1]], &qual[x[0]..x[1]]);
+                Self::read_layouts(&segment, layouts, opts)
+            })
+            .collect()
+    }
+
+    /// search the block downstream of the previous matched block on its strand,
+    /// limited to `max_gap` of the block if set
+    fn align_downstream(
+        read: &[u8],
+        masked: Option<&[u8]>,
+        block_info: &BlockInfo,
+        pre_align: Option<&Alignment>,
+    ) -> Option<Alignment> {
+        let read_len = read.len();
+        let max_dist = block_info.max_gap.map(|x| x + block_info.max_seq_len());
+        let (start, end) = match pre_align {
+            Some(pre) if pre.strand == Strand::Plus => {
+                let end = max_dist.map_or(read_len, |x| pre.query_end + x);
+                (pre.query_end, end.min(read_len))
+            }
+            Some(pre) if pre.strand == Strand::Minus => {
+                let start = max_dist.map_or(0, |x| pre.query_start.saturating_sub(x));
+                (start, pre.query_start)
+            }
+            _ => (0, read_len),
+        };
+        let mut align = block_info.align_range_masked(read, masked, start, end)?;
+        // a repeated motif takes its first occurrence in the search window,
+        // i.e. the nearest one to the previous block
+        loop {
+            let (upstream_start, upstream_end) = match align.strand {
+                Strand::Minus => (align.query_end, end),
+                _ => (start, align.query_start),
+            };
+            match block_info
+                .align_range_masked(read, masked, upstream_start, upstream_end)
+                .filter(|x| x.strand == align.strand)
+            {
+                Some(upstream) => align = upstream,
+                None => break,
+            }
+        }
+        Some(align)
+    }
+
+    /// search the block upstream of `align` in the orientation of its strand
+    fn align_upstream(
+        read: &[u8],
+        masked: Option<&[u8]>,
+        block_info: &BlockInfo,
+        align: &Alignment,
+    ) -> Option<Alignment> {
+        let (start, end) = match align.strand {
+            Strand::Minus => (align.query_end, read.len()),
+            _ => (0, align.query_start),
+        };
+        block_info
+            .align_range_masked(read, masked, start, end)
+            .filter(|x| x.strand == align.strand)
+    }
+
     /// get the block flag of the read
     pub fn get_block_flag(&self) -> usize {
         let mut flag = 0;
@@ -243,9 +810,27 @@ where
             } else {
             }
         }
+        if let Some(mate) = &self.mate {
+            flag |= mate.get_block_flag();
+        }
+        for (_, index_read) in self.index_reads.iter() {
+            flag |= index_read.get_block_flag();
+        }
         flag
     }
 
+    /// the line of the read in the read_info file
+    pub fn get_read_info(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.record.id(),
+            self.get_block_flag(),
+            self.get_block_str(),
+            self.status,
+            self.notes.join(";")
+        )
+    }
+
     /// tostring
     pub fn get_block_str(&self) -> String {
         let mut block_str_list: Vec<String> = vec![];
@@ -260,6 +845,13 @@ where
             } else {
             }
         }
+        let mates = self.mate.iter().map(|x| x.as_ref());
+        for mate in mates.chain(self.index_reads.iter().map(|x| &x.1)) {
+            let block_str = mate.get_block_str();
+            if !block_str.is_empty() {
+                block_str_list.push(block_str);
+            }
+        }
 
         block_str_list.join(";")
     }
@@ -322,7 +914,9 @@ where
     /// blockinfo
     /// 基于 readblockalign 生成 hashmap 用于对应模版
     pub fn get_seq_hashmap(&self) -> Option<HashMap<String, JinjaSeq>> {
-        if self.strand == Strand::Ambiguous {
+        if self.strand == Strand::Ambiguous
+            || matches!(self.status, ReadStatus::BadLength | ReadStatus::MissingBlock)
+        {
             return None;
         }
         let record = &self.record;
@@ -354,6 +948,7 @@ where
             "read".to_string(),
             JinjaSeq {
                 name: self.record.id().to_string(),
+                desc: to_str(self.record.desc().map(|x| x.to_string())),
                 seq: String::from_utf8(self.record.seq().to_vec()).unwrap_or("".to_string()),
                 qual: String::from_utf8(self.record.qual().to_vec()).unwrap_or("".to_string()),
                 ..Default::default()
@@ -368,8 +963,52 @@ where
 
     pub fn template_str(&self, template: &Template<'_, '_>) -> Option<String> {
         let seq_hash = self.get_seq_hashmap();
+        // the blocks on read 2 are `r2.<block>`, the read is undetermined if they are not resolved
+        let mate_seq_hash = match &self.mate {
+            Some(mate) => Some(mate.get_seq_hashmap()?),
+            None => None,
+        };
+        // the whole index read is named by I1/I2, its blocks by their own names
+        let mut index_seq_hash: HashMap<String, JinjaSeq> = HashMap::new();
+        for (read_type, index_read) in self.index_reads.iter() {
+            let record = &index_read.record;
+            if !index_read.block_idx_list.is_empty() {
+                // the index read without any hit has no strand, it is rendered like
+                // the read 1 if only the optional blocks are absent
+                let mut seq_hash = match index_read.get_seq_hashmap() {
+                    Some(seq_hash) => seq_hash,
+                    None if index_read.status == ReadStatus::Pass => index_read
+                        .block_idx_list
+                        .iter()
+                        .map(|x| (x.to_owned(), JinjaSeq::default()))
+                        .collect(),
+                    None => return None,
+                };
+                seq_hash.remove("read");
+                index_seq_hash.extend(seq_hash);
+            }
+            let seq = JinjaSeq::new(record.id(), record, 0, record.seq().len(), &Strand::Plus);
+            index_seq_hash.insert(read_type.to_string(), seq);
+        }
         let out = seq_hash.as_ref().map(|x| {
-            let ctx = Value::from_serializable(x);
+            let mut ctx: HashMap<String, Value> = x
+                .iter()
+                .map(|(k, v)| (k.to_owned(), Value::from_serializable(v)))
+                .collect();
+            let layout = self.layout.clone().unwrap_or_default();
+            ctx.insert("layout".to_string(), Value::from(layout));
+            if let Some(mate_seq_hash) = &mate_seq_hash {
+                ctx.insert("r2".to_string(), Value::from_serializable(mate_seq_hash));
+            }
+            for (k, v) in index_seq_hash.iter() {
+                ctx.insert(k.to_owned(), Value::from_serializable(v));
+            }
+            // the name and the metadata of the sample
+            if let Some(sample) = &self.sample {
+                let mut meta = sample.meta.clone();
+                meta.insert("name".to_string(), sample.name.to_owned());
+                ctx.insert("sample".to_string(), Value::from_serializable(&meta));
+            }
             template.render(ctx).expect("无法渲染模板")
         });
         out
@@ -477,7 +1116,8 @@ CCCCGACAAACGA
     let read_rev = revcomp(read);
     dbg!(std::str::from_utf8(&read_rev));
     let record = fastq::Record::with_attrs("read01_rev", None, read, read);
-    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec);
+    let read_block_align =
+        ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &ReadAlignOpts::default());
     let block_align = &read_block_align.block_align;
     for (k, v) in block_align {
         println!("{k}");
@@ -492,3 +1132,610 @@ CCCCGACAAACGA
     // dbg!(seq_hashmap);
     // dbg!(block_align);
 }
+
+#[test]
+fn test_read_block_info_ordered() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	query_start	query_end	seq_len	method	max_gap
+Fix_0	Fix	AAACCTGAGAAACCAT	0				SW	
+Variable_1	Variable		0				SW	
+Fix_2	Fix	GTCACGGGTATATGAG	0				SW	5";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts {
+        ordered: true,
+        ..Default::default()
+    };
+    let read = b"GTCACGGGTATATGAGTTTAAACCTGAGAAACCATCCGTCACGGGTATATGAG";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let ba = read_block_align.block_align["Fix_2"].as_ref().unwrap();
+    assert_eq!(ba.get_query_start(), Some(37));
+    let variable = read_block_align.block_align["Variable_1"].as_ref().unwrap();
+    assert_eq!((variable.get_query_start(), variable.get_query_end()), (Some(35), Some(37)));
+
+    // Fix_2 only appears upstream of Fix_0
+    let read = b"GTCACGGGTATATGAGTTTAAACCTGAGAAACCATCCCCCCC";
+    let record = fastq::Record::with_attrs("read02", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::OutOfOrder);
+    assert!(read_block_align.block_align["Fix_2"].is_none());
+
+    // Fix_2 is downstream but beyond max_gap, it is missing but not out of order
+    let read = b"AAACCTGAGAAACCATCCCCCCCCCCCCGTCACGGGTATATGAG";
+    let record = fastq::Record::with_attrs("read03", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::MissingBlock);
+    assert!(read_block_align.block_align["Fix_2"].is_none());
+}
+
+#[test]
+fn test_read_block_info_ordered_repeat() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW
+Fix_2	Fix	GTCACGGGTATATGAG	0	SW";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts {
+        ordered: true,
+        ..Default::default()
+    };
+    // Fix_0 appears twice, the first occurrence starts the layout
+    let read = b"AAACCTGAGAAACCATCCCCAAACCTGAGAAACCATTTTTGTCACGGGTATATGAG";
+    for (name, read) in [("read01", read.to_vec()), ("read02", revcomp(read))] {
+        let record = fastq::Record::with_attrs(name, None, &read, &read);
+        let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+        assert_eq!(read_block_align.status, ReadStatus::Pass);
+        let variable = read_block_align.block_align["Variable_1"].as_ref().unwrap();
+        let len = variable.get_query_end().unwrap() - variable.get_query_start().unwrap();
+        assert_eq!(len, 24);
+    }
+    // Fix_2 appears twice downstream of Fix_0, the nearest one is taken
+    let read = b"AAACCTGAGAAACCATCCGTCACGGGTATATGAGCCGTCACGGGTATATGAG";
+    let record = fastq::Record::with_attrs("read03", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let ba = read_block_align.block_align["Fix_2"].as_ref().unwrap();
+    assert_eq!(ba.get_query_start(), Some(18));
+}
+
+#[test]
+fn test_read_block_info_vote_strand() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW
+Fix_2	Fix	GTCACGGGTATATGAG	0	SW
+Fix_3	Fix	GTCATTTAGAACAACT	0	SW";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+>GTCATTTAGAACAACT
+GTCATTTAGAACAACT
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    // Fix_3 is on the minus strand
+    let mut read = b"AAACCTGAGAAACCATCCCGTCACGGGTATATGAG".to_vec();
+    read.extend(revcomp(b"GTCATTTAGAACAACT"));
+    let record = fastq::Record::with_attrs("read01", None, &read, &read);
+    let opts = ReadAlignOpts::default();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.strand, Strand::Plus);
+    assert!(read_block_align.block_align["Fix_3"].is_none());
+    assert_eq!(read_block_align.notes, vec!["discordant=Fix_3", "missing=Fix_3"]);
+    let variable = read_block_align.block_align["Variable_1"].as_ref().unwrap();
+    assert_eq!((variable.get_query_start(), variable.get_query_end()), (Some(16), Some(19)));
+
+    let opts = ReadAlignOpts {
+        min_strand_anchors: 3,
+        ..Default::default()
+    };
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.strand, Strand::Ambiguous);
+
+    // one anchor resolves the strand, but the read without the others is not rendered
+    let read = b"AAACCTGAGAAACCATCCCCCCCC";
+    let record = fastq::Record::with_attrs("read02", None, read, read);
+    let opts = ReadAlignOpts {
+        min_strand_anchors: 1,
+        ..Default::default()
+    };
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.strand, Strand::Plus);
+    assert_eq!(read_block_align.status, ReadStatus::MissingBlock);
+    let read_info = read_block_align.get_read_info();
+    assert!(read_info.ends_with("missing_block\tmissing=Variable_1,Fix_2,Fix_3"));
+    let env = minijinja::Environment::new();
+    let template = env.template_from_str("{{read.seq}} {{Fix_2.seq}}").unwrap();
+    assert!(read_block_align.template_str(&template).is_none());
+}
+
+#[test]
+fn test_segment_read() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let anchor = b"AAACCTGAGAAACCAT";
+    let mut read = anchor.to_vec();
+    read.extend(b"GGGGGGGG");
+    read.extend(anchor);
+    read.extend(b"TTTTTTTT");
+    read.extend(revcomp(&[anchor.to_vec(), b"CCCCCCCC".to_vec()].concat()));
+    let record = fastq::Record::with_attrs("read01", None, &read, &read);
+    let layouts = vec![Layout::new("layout", blockinfo_vec)];
+    let segments = ReadBlockAlign::segment_read(&record, &layouts, &ReadAlignOpts::default());
+    let names: Vec<&str> = segments.iter().map(|x| x.record.id()).collect();
+    assert_eq!(names, vec!["read01/1", "read01/2", "read01/3"]);
+    let variables: Vec<String> = segments
+        .iter()
+        .map(|x| x.get_seq_hashmap().unwrap()["Variable_1"].seq.to_owned())
+        .collect();
+    assert_eq!(variables, vec!["GGGGGGGG", "TTTTTTTT", "GGGGGGGG"]);
+    assert_eq!(segments[2].strand, Strand::Minus);
+}
+
+#[test]
+fn test_read_block_info_variable_len() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	min_len	max_len	exact_len
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW			
+Variable_1	Variable		0	SW	3	5	
+Fix_2	Fix	GTCACGGGTATATGAG	0	SW			
+Variable_3	Variable		0	SW			4";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts::default();
+    let read = b"AAACCTGAGAAACCATCCCCGTCACGGGTATATGAGTTTT";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    assert!(read_block_align.get_seq_hashmap().is_some());
+
+    // Variable_1 is too short and Variable_3 is too long
+    let read = b"AAACCTGAGAAACCATCCGTCACGGGTATATGAGTTTTT";
+    let record = fastq::Record::with_attrs("read02", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::BadLength);
+    assert_eq!(read_block_align.notes, vec!["bad_length=Variable_1:2,Variable_3:5"]);
+    assert!(read_block_align.get_seq_hashmap().is_none());
+}
+
+#[test]
+fn test_read_block_info_fixed_len_variable() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	seq_len	offset
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW		
+UMI	Variable		0	SW	4	2
+cDNA	Variable		0	SW		";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts::default();
+    let read = b"GGAAACCTGAGAAACCATTTACGTCCCCCCCCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let umi = read_block_align.block_align["UMI"].as_ref().unwrap();
+    assert_eq!((umi.get_query_start(), umi.get_query_end()), (Some(20), Some(24)));
+    let cdna = read_block_align.block_align["cDNA"].as_ref().unwrap();
+    assert_eq!((cdna.get_query_start(), cdna.get_query_end()), (Some(24), Some(34)));
+
+    // the same layout on the minus strand
+    let read_rev = revcomp(read);
+    let record = fastq::Record::with_attrs("read02", None, &read_rev, &read_rev);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    let umi = read_block_align.block_align["UMI"].as_ref().unwrap();
+    assert_eq!((umi.get_query_start(), umi.get_query_end()), (Some(10), Some(14)));
+    let cdna = read_block_align.block_align["cDNA"].as_ref().unwrap();
+    assert_eq!((cdna.get_query_start(), cdna.get_query_end()), (Some(0), Some(10)));
+
+    // the read ends in the UMI
+    let read = b"GGAAACCTGAGAAACCATTTAC";
+    let record = fastq::Record::with_attrs("read03", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::BadLength);
+}
+
+#[test]
+fn test_read_block_info_optional() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	optional
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+Variable_1	Variable		0	SW	
+Spacer	Fix	GTCATTTAGAACAACT	0	SW	true
+Fix_2	Fix	GTCACGGGTATATGAG	0	SW	
+Variable_3	Variable		0	SW	";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+>GTCATTTAGAACAACT
+GTCATTTAGAACAACT
+";
+    let opts = ReadAlignOpts::default();
+    let read = b"AAACCTGAGAAACCATCCCCGTCACGGGTATATGAGTTTT";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert!(read_block_align.block_align["Spacer"].is_none());
+    let variable = read_block_align.block_align["Variable_1"].as_ref().unwrap();
+    assert_eq!((variable.get_query_start(), variable.get_query_end()), (Some(16), Some(20)));
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let seq_hash = read_block_align.get_seq_hashmap().unwrap();
+    assert_eq!(seq_hash["Spacer"].seq, "");
+
+    // the required block breaks its neighbouring variable block, the read is not rendered
+    let blockinfo_str = blockinfo_str.replace("SW	true", "SW	false");
+    let blockinfo_vec = get_block_info_fasta(&blockinfo_str, fasta_file).unwrap();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert!(read_block_align.block_align["Variable_1"].is_none());
+    assert_eq!(read_block_align.status, ReadStatus::MissingBlock);
+    assert_eq!(read_block_align.notes, vec!["missing=Variable_1,Spacer"]);
+    assert!(read_block_align.get_seq_hashmap().is_none());
+}
+
+#[test]
+fn test_read_layouts() {
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+>GTCATTTAGAACAACT
+GTCATTTAGAACAACT
+";
+    let blockinfo_a = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW";
+    let blockinfo_b = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Index	Index	GTCACGGGTATATGAG,GTCATTTAGAACAACT	0	SW
+Variable_1	Variable		0	SW";
+    let layouts: Vec<Layout> = [("a", blockinfo_a), ("b", blockinfo_b)]
+        .iter()
+        .map(|(name, blockinfo_str)| {
+            Layout::new(name, get_block_info_fasta(blockinfo_str, fasta_file).unwrap())
+        })
+        .collect();
+    let opts = ReadAlignOpts::default();
+    // without the index, layout a has no absent block
+    let read = b"AAACCTGAGAAACCATCCCCCCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    assert_eq!(read_block_align.layout, Some("a".to_string()));
+    assert_eq!(read_block_align.notes, vec!["layout=a"]);
+
+    let read = b"AAACCTGAGAAACCATGTCATTTAGAACAACTCCCCCCCC";
+    let record = fastq::Record::with_attrs("read02", None, read, read);
+    let read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    assert_eq!(read_block_align.layout, Some("b".to_string()));
+    assert_eq!(read_block_align.get_best_index(), vec!["GTCATTTAGAACAACT"]);
+    let variable = read_block_align.block_align["Variable_1"].as_ref().unwrap();
+    assert_eq!(variable.get_query_start(), Some(32));
+
+    // a block of an unknown seq_type is absent instead of a panic
+    let blockinfo_c = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	fix	AAACCTGAGAAACCAT	0	SW";
+    let layouts = vec![
+        layouts[0].clone(),
+        Layout::new("c", get_block_info_fasta(blockinfo_c, fasta_file).unwrap()),
+    ];
+    let read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    assert_eq!(read_block_align.layout, Some("a".to_string()));
+}
+
+#[test]
+fn test_read_pair_layouts() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	read
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+Variable_1	Variable		0	SW	R1
+Index	Index	GTCACGGGTATATGAG,GTCATTTAGAACAACT	0	SW	R2
+UMI	Variable		0	SW	R2";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGGTATATGAG
+GTCACGGGTATATGAG
+>GTCATTTAGAACAACT
+GTCATTTAGAACAACT
+";
+    let layouts = vec![Layout::new(
+        "layout",
+        get_block_info_fasta(blockinfo_str, fasta_file).unwrap(),
+    )];
+    assert_eq!(layouts[0].r2_block_info_list.len(), 2);
+    let read = b"AAACCTGAGAAACCATCCCCCCCC";
+    let record_r1 = fastq::Record::with_attrs("read01", None, read, read);
+    let read = b"GTCATTTAGAACAACTACGTACGT";
+    let record_r2 = fastq::Record::with_attrs("read01", None, read, read);
+    let opts = ReadAlignOpts::default();
+    let read_block_align =
+        ReadBlockAlign::read_pair_layouts(&record_r1, &record_r2, &layouts, &opts);
+    assert_eq!(read_block_align.get_best_index(), vec!["GTCATTTAGAACAACT"]);
+    let env = minijinja::Environment::new();
+    let template = env
+        .template_from_str("{{Variable_1.seq}} {{r2.UMI.seq}} {{r2.read.name}}")
+        .unwrap();
+    let out = read_block_align.template_str(&template).unwrap();
+    assert_eq!(out, "CCCCCCCC ACGTACGT read01");
+
+    // the read 2 without the index is undetermined
+    let record_r2 = fastq::Record::with_attrs("read02", None, b"ACGTACGT", b"ACGTACGT");
+    let read_block_align =
+        ReadBlockAlign::read_pair_layouts(&record_r1, &record_r2, &layouts, &opts);
+    assert!(read_block_align.template_str(&template).is_none());
+}
+
+#[test]
+fn test_read_pair_auto_swap() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+";
+    let layouts = vec![Layout::new(
+        "layout",
+        get_block_info_fasta(blockinfo_str, fasta_file).unwrap(),
+    )];
+    let opts = ReadAlignOpts::default();
+    let read = b"GGGGGGGGTTTTTTTTCCCCCCCC";
+    let record_r1 = fastq::Record::with_attrs("read01", None, read, read);
+    let read = b"AAACCTGAGAAACCATACGTACGT";
+    let record_r2 = fastq::Record::with_attrs("read01", None, read, read);
+    let (read_block_align, swapped) =
+        ReadBlockAlign::read_pair_auto_swap(&record_r1, &record_r2, &layouts, &opts);
+    assert!(swapped);
+    assert_eq!(read_block_align.notes, vec!["swapped"]);
+    assert_eq!(read_block_align.record.seq(), record_r2.seq());
+
+    let (_, swapped) =
+        ReadBlockAlign::read_pair_auto_swap(&record_r2, &record_r1, &layouts, &opts);
+    assert!(!swapped);
+}
+
+#[test]
+fn test_align_index_reads() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	read
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	R1
+Variable_1	Variable		0	SW	R1
+Sample	Index	GTCACGGG,GTCATTTA	1	HASH	I1";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGG
+GTCACGGG
+>GTCATTTA
+GTCATTTA
+";
+    let layouts = vec![Layout::new(
+        "layout",
+        get_block_info_fasta(blockinfo_str, fasta_file).unwrap(),
+    )];
+    let opts = ReadAlignOpts::default();
+    let read = b"AAACCTGAGAAACCATCCCCCCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let index_records = vec![
+        (ReadType::I1, fastq::Record::with_attrs("read01", None, b"GTCATTAA", b"IIIIIIII")),
+        (ReadType::I2, fastq::Record::with_attrs("read01", None, b"ACGTACGT", b"IIIIIIII")),
+    ];
+    let mut read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    read_block_align.align_index_reads(&index_records, &layouts, &opts);
+    assert_eq!(read_block_align.get_best_index(), vec!["GTCATTTA"]);
+    let env = minijinja::Environment::new();
+    let template = env
+        .template_from_str("{{read.seq}} {{Sample.name}} {{I1.seq}} {{I2.seq}}")
+        .unwrap();
+    let out = read_block_align.template_str(&template).unwrap();
+    assert_eq!(out, "AAACCTGAGAAACCATCCCCCCCC GTCATTTA GTCATTAA ACGTACGT");
+
+    // the missing Index on I1 is handled like on the read 1
+    let index_records = vec![
+        (ReadType::I1, fastq::Record::with_attrs("read01", None, b"AAAAAAAA", b"IIIIIIII")),
+        (ReadType::I2, fastq::Record::with_attrs("read01", None, b"ACGTACGT", b"IIIIIIII")),
+    ];
+    let mut read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    read_block_align.align_index_reads(&index_records, &layouts, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::MissingBlock);
+    assert_eq!(read_block_align.notes, vec!["I1.missing=Sample"]);
+    assert!(read_block_align.template_str(&template).is_none());
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	read	optional
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	R1	
+Variable_1	Variable		0	SW	R1	
+Sample	Index	GTCACGGG,GTCATTTA	1	HASH	I1	true";
+    let layouts = vec![Layout::new(
+        "layout",
+        get_block_info_fasta(blockinfo_str, fasta_file).unwrap(),
+    )];
+    let mut read_block_align = ReadBlockAlign::read_layouts(&record, &layouts, &opts);
+    read_block_align.align_index_reads(&index_records, &layouts, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let out = read_block_align.template_str(&template).unwrap();
+    assert_eq!(out, "AAACCTGAGAAACCATCCCCCCCC  AAAAAAAA ACGTACGT");
+}
+
+#[test]
+fn test_assign_sample() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	optional
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+i7	Index	GTCACGGG,TTAGGCAA	0	HASH	true
+i5	Index	ACGTACGT,CATGCATG	0	HASH	true";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGG
+GTCACGGG
+>TTAGGCAA
+TTAGGCAA
+>ACGTACGT
+ACGTACGT
+>CATGCATG
+CATGCATG
+";
+    let block_info_list = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let index_names = vec!["i7".to_string(), "i5".to_string()];
+    let sheet_str = "sample	i7	i5
+s1	GTCACGGG	ACGTACGT";
+    let sheet = SampleSheet::from_tsv(sheet_str, &index_names).unwrap();
+    let opts = ReadAlignOpts::default();
+    let assign = |read: &[u8]| {
+        let record = fastq::Record::with_attrs("read01", None, read, read);
+        let mut read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+        read_block_align.assign_sample(&sheet);
+        (read_block_align.status, read_block_align.sample.map(|x| x.name))
+    };
+    let pass = assign(b"AAACCTGAGAAACCATGTCACGGGACGTACGTCC");
+    assert_eq!(pass, (ReadStatus::Pass, Some("s1".to_string())));
+    let unexpected = assign(b"AAACCTGAGAAACCATGTCACGGGCATGCATGCC");
+    assert_eq!(unexpected, (ReadStatus::UnexpectedCombination, None));
+    // the i5 is not found
+    let incomplete = assign(b"AAACCTGAGAAACCATGTCACGGGTTTTTTTTCC");
+    assert_eq!(incomplete, (ReadStatus::IncompleteIndex, None));
+}
+
+#[test]
+fn test_read_block_info_ambiguous_index() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	min_margin
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+Sample	Index	GTCACGGG,GTCACGTA	2	HASH	3";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGG
+GTCACGGG
+>GTCACGTA
+GTCACGTA
+";
+    let block_info_list = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts::default();
+    // one mismatch to both sequences
+    let read = b"AAACCTGAGAAACCATGTCACGGACCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::AmbiguousIndex);
+    assert!(read_block_align.notes.contains(&"ambiguous=Sample".to_string()));
+    let read = b"AAACCTGAGAAACCATGTCACGGGCCCC";
+    let record = fastq::Record::with_attrs("read02", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    assert_eq!(read_block_align.get_best_index(), vec!["GTCACGGG"]);
+
+    // a tie is ambiguous without min_margin
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Sample	Index	GTCACGGG,GTCACGTA	2	HASH";
+    let block_info_list = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let read = b"AAACCTGAGAAACCATGTCACGGACCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::AmbiguousIndex);
+}
+
+#[test]
+fn test_annotate_umi() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	seq_len
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+UMI_1	UMI		0	SW	8
+Variable_2	Variable		0	SW	";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+";
+    let block_info_list = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let opts = ReadAlignOpts::default();
+    let read = b"AAACCTGAGAAACCATAACCGTTTCCCCCCCC";
+    let qual = b"IIIIIIIIIIIIIIIIABCDEFGHIIIIIIII";
+    let record = fastq::Record::with_attrs("read000", None, read, qual);
+    let mut read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    let umi = ("AACCGTTT".to_string(), "ABCDEFGH".to_string());
+    assert_eq!(read_block_align.get_umi(), Some(umi.clone()));
+    read_block_align.annotate_umi(&UmiMode::Name);
+    assert_eq!(read_block_align.record.id(), "read000_AACCGTTT");
+    let tagged = annotate_umi(&record, &umi, &UmiMode::Tag);
+    assert_eq!(tagged.desc(), Some("RX:Z:AACCGTTT\tQX:Z:ABCDEFGH"));
+    // the tags are in the rendered header when the template writes read.desc
+    let mut read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    read_block_align.annotate_umi(&UmiMode::Tag);
+    let env = minijinja::Environment::new();
+    let template = env.template_from_str(">{{read.name}}{% if read.desc %} {{read.desc}}{% endif %}\n{{read.seq}}").unwrap();
+    let out = read_block_align.template_str(&template).unwrap();
+    assert!(out.starts_with(">read000 RX:Z:AACCGTTT\tQX:Z:ABCDEFGH\n"));
+    // the reverse strand UMI is in the orientation of the layout
+    let record = fastq::Record::with_attrs("read001", None, &revcomp(read), qual);
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+    assert_eq!(read_block_align.get_umi().unwrap().0, "AACCGTTT");
+    // the fasta comment is only written for the UMI tags
+    let record = bio::io::fasta::Record::with_attrs("read002", Some("comment"), read);
+    assert_eq!(record.to_str(), format!(">read002\n{}\n", String::from_utf8_lossy(read)));
+    let tagged = annotate_umi(&record, &umi, &UmiMode::Tag);
+    assert!(tagged.to_str().starts_with(">read002 RX:Z:AACCGTTT\tQX:Z:ABCDEFGH\n"));
+}
+
+#[test]
+fn test_read_block_info_many_low_qual() {
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Sample	Index	GTCACGGG,TTAGGCAA	1	HASH";
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>GTCACGGG
+GTCACGGG
+>TTAGGCAA
+TTAGGCAA
+";
+    let block_info_list = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read = b"AAACCTGAGAAACCATGTCACGGGCCCC";
+    // 5 bases of the Index at Q2, more masked bases than the HASH lookup expands
+    let qual = b"IIIIIIIIIIIIIIII###I##IICCCC";
+    let record = fastq::Record::with_attrs("read01", None, read, qual);
+    for min_qual in [None, Some(20)] {
+        let opts = ReadAlignOpts {
+            min_qual,
+            ..Default::default()
+        };
+        let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_list, &opts);
+        assert_eq!(read_block_align.get_best_index(), vec!["GTCACGGG"]);
+    }
+}
+
+#[test]
+fn test_read_block_info_overlapped_neighbours() {
+    let fasta_file = ">AAACCTGAGAAACCAT
+AAACCTGAGAAACCAT
+>CCATGTCACGGG
+CCATGTCACGGG
+";
+    // Fix_2 overlaps the last 4 bases of Fix_0
+    let read = b"AAACCTGAGAAACCATGTCACGGGTTTT";
+    let record = fastq::Record::with_attrs("read01", None, read, read);
+    let opts = ReadAlignOpts::default();
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW
+Variable_1	Variable		0	SW
+Fix_2	Fix	CCATGTCACGGG	0	SW";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::Pass);
+    let seq_hash = read_block_align.get_seq_hashmap().unwrap();
+    assert_eq!(seq_hash["Variable_1"].seq, "");
+    // the overlap is a bad length with a length range
+    let blockinfo_str = "idx	seq_type	fasta_seq_id	max_mismatch	method	min_len
+Fix_0	Fix	AAACCTGAGAAACCAT	0	SW	
+Variable_1	Variable		0	SW	0
+Fix_2	Fix	CCATGTCACGGG	0	SW	";
+    let blockinfo_vec = get_block_info_fasta(blockinfo_str, fasta_file).unwrap();
+    let read_block_align = ReadBlockAlign::read_block_info(&record, &blockinfo_vec, &opts);
+    assert_eq!(read_block_align.status, ReadStatus::BadLength);
+    assert_eq!(read_block_align.notes, vec!["bad_length=Variable_1:-4"]);
+}
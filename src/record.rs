@@ -7,8 +7,11 @@ use std::io::Write;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use super::blockinfo::BlockInfo;
-use super::readblockalign::ReadBlockAlign;
+use super::blockinfo::{Layout, ReadType};
+use super::indexstat::IndexStat;
+use super::readblockalign::{annotate_umi, ReadAlignOpts, ReadBlockAlign, ReadStatus};
+use super::samplesheet::SampleSheet;
+use super::utils::merge_pair;
 
 pub trait Record {
     fn id(&self) -> &str;
@@ -16,6 +19,10 @@ pub trait Record {
     fn desc(&self) -> Option<&str>;
     fn qual(&self) -> Vec<u8>;
     fn to_str(&self) -> String;
+    /// a new record of the same type with the given name, sequence and quality
+    fn with_seq(&self, id: &str, seq: &[u8], qual: &[u8]) -> Self;
+    /// a new record of the same type with the given name and comment
+    fn with_name(&self, id: &str, desc: Option<&str>) -> Self;
 }
 
 impl Record for fasta::Record {
@@ -33,11 +40,18 @@ impl Record for fasta::Record {
     fn desc(&self) -> Option<&str> {
         self.desc()
     }
+    fn with_seq(&self, id: &str, seq: &[u8], _qual: &[u8]) -> Self {
+        fasta::Record::with_attrs(id, self.desc(), seq)
+    }
+    fn with_name(&self, id: &str, desc: Option<&str>) -> Self {
+        fasta::Record::with_attrs(id, desc, self.seq())
+    }
     fn to_str(&self) -> String{
-        format!(">{}\n{}\n",
-        self.id(),
-        String::from_utf8_lossy(self.seq()),
-        )
+        // the comment isn't written except the UMI tags
+        match self.desc().and_then(|x| x.find("RX:Z:").map(|ii| &x[ii..])) {
+            Some(desc) => format!(">{} {}\n{}\n", self.id(), desc, String::from_utf8_lossy(self.seq())),
+            None => format!(">{}\n{}\n", self.id(), String::from_utf8_lossy(self.seq())),
+        }
     }
 }
 
@@ -55,6 +69,12 @@ impl Record for fastq::Record {
     fn desc(&self) -> Option<&str> {
         self.desc()
     }
+    fn with_seq(&self, id: &str, seq: &[u8], qual: &[u8]) -> Self {
+        fastq::Record::with_attrs(id, self.desc(), seq, qual)
+    }
+    fn with_name(&self, id: &str, desc: Option<&str>) -> Self {
+        fastq::Record::with_attrs(id, desc, self.seq(), self.qual())
+    }
     fn to_str(&self) -> String{
         format!("@{} {}\n{}\n+\n{}\n",
         self.id(),
@@ -71,24 +91,127 @@ pub struct FastxRecord<R> {
     pub record2: Option<R>,
 }
 
+/// the output files and the statistics shared by the threads processing the records
+#[derive(Clone)]
+pub struct RecordOutputs {
+    pub out_fq_handle_vec: Arc<Mutex<Vec<File>>>,
+    pub ud_fq_handle_vec: Arc<Mutex<Vec<File>>>,
+    /// the merged pairs are written as single reads
+    pub merged_fq_handle_vec: Arc<Mutex<Vec<File>>>,
+    pub merged_ud_fq_handle_vec: Arc<Mutex<Vec<File>>>,
+    pub read_info_handle: Arc<Mutex<File>>,
+    pub flag_stat_hash: Arc<Mutex<HashMap<usize, usize>>>,
+    pub status_stat_hash: Arc<Mutex<HashMap<String, usize>>>,
+    pub index_stat_hash: Arc<Mutex<IndexStat>>,
+}
+
+impl RecordOutputs {
+    /// the outputs of the merged pairs, which are processed as single reads
+    fn merged(&self) -> Self {
+        Self {
+            out_fq_handle_vec: self.merged_fq_handle_vec.clone(),
+            ud_fq_handle_vec: self.merged_ud_fq_handle_vec.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+/// the output prefix and the demultiplexed name of the read, named by the sample if
+/// a sample sheet is given, the reads of every layout go to their own files named by the layout,
+/// the reads with an ambiguous Index go to the ambiguous files, and the reads missing
+/// an Index of the sample sheet go to the incomplete_index files
+fn output_name<R: Record + Clone>(
+    read_block_align: &ReadBlockAlign<R>,
+    prefix: &str,
+) -> (String, Vec<String>) {
+    let best_index_vec = if read_block_align.status == ReadStatus::AmbiguousIndex {
+        vec!["ambiguous".to_string()]
+    } else if let Some(sample) = &read_block_align.sample {
+        vec![sample.name.to_owned()]
+    } else if read_block_align.status == ReadStatus::UnexpectedCombination {
+        vec!["unexpected_combination".to_string()]
+    } else if read_block_align.status == ReadStatus::IncompleteIndex {
+        vec!["incomplete_index".to_string()]
+    } else {
+        read_block_align.get_best_index()
+    };
+    match &read_block_align.layout {
+        Some(layout) if best_index_vec.is_empty() => {
+            (format!("{}.{}", prefix, layout), vec!["template".to_string()])
+        }
+        Some(layout) => (format!("{}.{}", prefix, layout), best_index_vec),
+        None => (prefix.to_string(), best_index_vec),
+    }
+}
+
 pub fn process_record_pair<R: Record + Clone>(
     record_r1: R,
     record_r2: R,
+    index_records: &[(ReadType, R)],
     ext: &str,
-    block_info_list: &[BlockInfo],
+    layouts: &[Layout],
+    opts: &ReadAlignOpts,
+    sample_sheet: Option<&SampleSheet>,
     prefix: &str,
     outdir: &Path,
     barcode_handle_hash: Arc<Mutex<DashMap<String, Vec<File>>>>,
     template: Template<'_, '_>,
-    out_fq_handle_vec: Arc<Mutex<Vec<File>>>,
-    ud_fq_handle_vec: Arc<Mutex<Vec<File>>>,
-    read_info_handle: Arc<Mutex<File>>,
-    flag_stat_hash: Arc<Mutex<HashMap<usize, usize>>>,
+    outputs: RecordOutputs,
 ) {
-    let read_name = record_r1.id();
-    let read_block_align = ReadBlockAlign::read_block_info(&record_r1, &block_info_list);
+    // the overlapping pair is merged and processed as a single read to the merged outputs,
+    // the others fall back to the pair
+    if opts.merge {
+        let merged = merge_pair(
+            record_r1.seq(),
+            &record_r1.qual(),
+            record_r2.seq(),
+            &record_r2.qual(),
+            opts.min_overlap,
+        );
+        if let Some((seq, qual)) = merged {
+            let record = record_r1.with_seq(record_r1.id(), &seq, &qual);
+            process_record_single(
+                record,
+                index_records,
+                ext,
+                layouts,
+                opts,
+                sample_sheet,
+                &format!("{}.{}", prefix, "merged"),
+                outdir,
+                barcode_handle_hash,
+                template,
+                outputs.merged(),
+            );
+            return;
+        }
+    }
+    let (mut read_block_align, swapped) = if opts.auto_swap {
+        ReadBlockAlign::read_pair_auto_swap(&record_r1, &record_r2, layouts, opts)
+    } else {
+        let read_block_align =
+            ReadBlockAlign::read_pair_layouts(&record_r1, &record_r2, layouts, opts);
+        (read_block_align, false)
+    };
+    read_block_align.align_index_reads(index_records, layouts, opts);
+    if let Some(sample_sheet) = sample_sheet {
+        read_block_align.assign_sample(sample_sheet);
+    }
+    // the swapped pair is written in the orientation of the layout
+    let (mut record_r1, mut record_r2) = if swapped {
+        (record_r2, record_r1)
+    } else {
+        (record_r1, record_r2)
+    };
+    // the UMI goes to the headers of both reads in every output
+    if let Some(umi_mode) = &opts.umi {
+        if let Some(umi) = read_block_align.annotate_umi(umi_mode) {
+            record_r1 = annotate_umi(&record_r1, &umi, umi_mode);
+            record_r2 = annotate_umi(&record_r2, &umi, umi_mode);
+        }
+    }
     let flag = read_block_align.get_block_flag();
-    let best_index_vec = read_block_align.get_best_index();
+    let (prefix, best_index_vec) = output_name(&read_block_align, prefix);
     // if barcode index existed, demultiplex
     if !best_index_vec.is_empty() {
         let best_index_str = best_index_vec.join("_");
@@ -104,7 +227,11 @@ pub fn process_record_pair<R: Record + Clone>(
                 ]
             });
         let template_str = read_block_align.template_str(&template);
-        if let Some(template_str) = template_str {
+        if read_block_align.status == ReadStatus::AmbiguousIndex {
+            // the ambiguous reads are written raw like the undetermined ones
+            write!(&barcode_handle[0], "{}", record_r1.to_str()).unwrap();
+            write!(&barcode_handle[1], "{}", record_r2.to_str()).unwrap();
+        } else if let Some(template_str) = template_str {
             writeln!(
                 barcode_handle.get(0).unwrap(),
                 "{}",
@@ -117,7 +244,7 @@ pub fn process_record_pair<R: Record + Clone>(
             )
             .unwrap();
         } else {
-            let out_fq_handle = ud_fq_handle_vec.lock().unwrap();
+            let out_fq_handle = outputs.ud_fq_handle_vec.lock().unwrap();
             write!(
                 out_fq_handle.get(0).unwrap(),
                 "{}", record_r1.to_str()
@@ -135,7 +262,7 @@ pub fn process_record_pair<R: Record + Clone>(
         let template_str = read_block_align.template_str(&template);
 
         if let Some(template_str) = template_str {
-            let out_fq_handle = out_fq_handle_vec.lock().unwrap();
+            let out_fq_handle = outputs.out_fq_handle_vec.lock().unwrap();
             // println!("{}", &template_str);
             writeln!(out_fq_handle.get(0).unwrap(), "{}", template_str).unwrap();
             write!(
@@ -144,7 +271,7 @@ pub fn process_record_pair<R: Record + Clone>(
             )
             .unwrap();
         } else {
-            let out_fq_handle = ud_fq_handle_vec.lock().unwrap();
+            let out_fq_handle = outputs.ud_fq_handle_vec.lock().unwrap();
             write!(
                 out_fq_handle.get(0).unwrap(),
                 "{}", record_r1.to_str()
@@ -159,91 +286,131 @@ pub fn process_record_pair<R: Record + Clone>(
         }
     }
 
-    let output_merge_str = read_block_align.get_block_str();
-
     writeln!(
-        read_info_handle.lock().unwrap(),
-        "{}\t{}\t{}",
-        read_name,
-        flag,
-        output_merge_str
+        outputs.read_info_handle.lock().unwrap(),
+        "{}",
+        read_block_align.get_read_info()
     )
     .unwrap();
-    *flag_stat_hash.lock().unwrap().entry(flag).or_insert(0) += 1;
+    *outputs.flag_stat_hash.lock().unwrap().entry(flag).or_insert(0) += 1;
+    *outputs.status_stat_hash
+        .lock()
+        .unwrap()
+        .entry(read_block_align.status.to_string())
+        .or_insert(0) += 1;
+    // the combinations of two or more Index blocks for the index pair report
+    let index_hits = read_block_align.get_index_hits();
+    if index_hits.len() > 1 && read_block_align.status != ReadStatus::AmbiguousIndex {
+        *outputs.index_stat_hash
+            .lock()
+            .unwrap()
+            .entry(index_hits.into_iter().collect())
+            .or_insert(0) += 1;
+    }
 }
 
 pub fn process_record_single<R: Record + Clone>(
     record_r1: R,
+    index_records: &[(ReadType, R)],
     ext: &str,
-    block_info_list: &[BlockInfo],
+    layouts: &[Layout],
+    opts: &ReadAlignOpts,
+    sample_sheet: Option<&SampleSheet>,
     prefix: &str,
     outdir: &Path,
     barcode_handle_hash: Arc<Mutex<DashMap<String, Vec<File>>>>,
     template: Template<'_, '_>,
-    out_fq_handle_vec: Arc<Mutex<Vec<File>>>,
-    ud_fq_handle_vec: Arc<Mutex<Vec<File>>>,
-    read_info_handle: Arc<Mutex<File>>,
-    flag_stat_hash: Arc<Mutex<HashMap<usize, usize>>>,
+    outputs: RecordOutputs,
 ) {
     // let _ = record;
 
     // let record_r1 = record.record1;
     // let record_r2 = record.record2.unwrap();
-    let read_name = record_r1.id();
-    let read_block_align = ReadBlockAlign::read_block_info(&record_r1, &block_info_list);
-    let flag = read_block_align.get_block_flag();
-    let best_index_vec = read_block_align.get_best_index();
-    // let outdir = outdir.to_string();
-    // if barcode index existed, demultiplex
-    if !best_index_vec.is_empty() {
-        let best_index_str = best_index_vec.join("_");
-        let barcode_file = outdir.join(format!("{}.{}.{}", prefix, best_index_str, ext));
-        let barcode_handle_hash = barcode_handle_hash.lock().unwrap();
-        let barcode_handle = barcode_handle_hash
-            .entry(barcode_file.to_str().unwrap().to_string())
-            .or_insert_with(|| vec![File::create(barcode_file).unwrap()]);
-        let template_str = read_block_align.template_str(&template);
-        if let Some(template_str) = template_str {
-            writeln!(
-                barcode_handle.get(0).unwrap(),
-                "{}",
-                template_str
-            )
-            .unwrap();
-        } else {
-            let out_fq_handle = ud_fq_handle_vec.lock().unwrap();
-            writeln!(
-                out_fq_handle.get(0).unwrap(),
-                "{}", record_r1.to_str()
-            )
-            .unwrap();
-        }
+    // every segment of the concatemer read is output as a single read
+    let mut read_block_align_vec = if opts.segment {
+        ReadBlockAlign::segment_read(&record_r1, layouts, opts)
     } else {
-        // 没有 barcode 拆分
-        // export to file based on the jinja template
-        let template_str = read_block_align.template_str(&template);
-
-        if let Some(template_str) = template_str {
-            let out_fq_handle = out_fq_handle_vec.lock().unwrap();
-            // println!("{}", &template_str);
-            writeln!(out_fq_handle.get(0).unwrap(), "{}", template_str).unwrap();
+        vec![ReadBlockAlign::read_layouts(&record_r1, layouts, opts)]
+    };
+    for read_block_align in read_block_align_vec.iter_mut() {
+        read_block_align.align_index_reads(index_records, layouts, opts);
+        if let Some(sample_sheet) = sample_sheet {
+            read_block_align.assign_sample(sample_sheet);
+        }
+        if let Some(umi_mode) = &opts.umi {
+            read_block_align.annotate_umi(umi_mode);
+        }
+    }
+    for read_block_align in read_block_align_vec.iter() {
+        let record_r1 = &read_block_align.record;
+        let flag = read_block_align.get_block_flag();
+        let (prefix, best_index_vec) = output_name(read_block_align, prefix);
+        // let outdir = outdir.to_string();
+        // if barcode index existed, demultiplex
+        if !best_index_vec.is_empty() {
+            let best_index_str = best_index_vec.join("_");
+            let barcode_file = outdir.join(format!("{}.{}.{}", prefix, best_index_str, ext));
+            let barcode_handle_hash = barcode_handle_hash.lock().unwrap();
+            let barcode_handle = barcode_handle_hash
+                .entry(barcode_file.to_str().unwrap().to_string())
+                .or_insert_with(|| vec![File::create(barcode_file).unwrap()]);
+            let template_str = read_block_align.template_str(&template);
+            if read_block_align.status == ReadStatus::AmbiguousIndex {
+                // the ambiguous reads are written raw like the undetermined ones
+                write!(&barcode_handle[0], "{}", record_r1.to_str()).unwrap();
+            } else if let Some(template_str) = template_str {
+                writeln!(
+                    barcode_handle.get(0).unwrap(),
+                    "{}",
+                    template_str
+                )
+                .unwrap();
+            } else {
+                let out_fq_handle = outputs.ud_fq_handle_vec.lock().unwrap();
+                writeln!(
+                    out_fq_handle.get(0).unwrap(),
+                    "{}", record_r1.to_str()
+                )
+                .unwrap();
+            }
         } else {
-            let out_fq_handle = ud_fq_handle_vec.lock().unwrap();
-            write!(
-                out_fq_handle.get(0).unwrap(),
-                "{}", record_r1.to_str()
-            )
-            .unwrap();
+            // 没有 barcode 拆分
+            // export to file based on the jinja template
+            let template_str = read_block_align.template_str(&template);
+
+            if let Some(template_str) = template_str {
+                let out_fq_handle = outputs.out_fq_handle_vec.lock().unwrap();
+                // println!("{}", &template_str);
+                writeln!(out_fq_handle.get(0).unwrap(), "{}", template_str).unwrap();
+            } else {
+                let out_fq_handle = outputs.ud_fq_handle_vec.lock().unwrap();
+                write!(
+                    out_fq_handle.get(0).unwrap(),
+                    "{}", record_r1.to_str()
+                )
+                .unwrap();
+            }
+        }
+        writeln!(
+            outputs.read_info_handle.lock().unwrap(),
+            "{}",
+            read_block_align.get_read_info()
+        )
+        .unwrap();
+        *outputs.flag_stat_hash.lock().unwrap().entry(flag).or_insert(0) += 1;
+        *outputs.status_stat_hash
+            .lock()
+            .unwrap()
+            .entry(read_block_align.status.to_string())
+            .or_insert(0) += 1;
+        // the combinations of two or more Index blocks for the index pair report
+        let index_hits = read_block_align.get_index_hits();
+        if index_hits.len() > 1 && read_block_align.status != ReadStatus::AmbiguousIndex {
+            *outputs.index_stat_hash
+                .lock()
+                .unwrap()
+                .entry(index_hits.into_iter().collect())
+                .or_insert(0) += 1;
         }
     }
-    let output_merge_str = read_block_align.get_block_str();
-    writeln!(
-        read_info_handle.lock().unwrap(),
-        "{}\t{}\t{}",
-        read_name,
-        flag,
-        output_merge_str
-    )
-    .unwrap();
-    *flag_stat_hash.lock().unwrap().entry(flag).or_insert(0) += 1;
 }
@@ -0,0 +1,109 @@
+use anyhow::{bail, Result};
+use csv::ReaderBuilder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+/// a sample of the sample sheet and its metadata columns
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// every column of the sample sheet, including the sample and the index columns
+    pub meta: HashMap<String, String>,
+}
+
+/// map the combinations of the Index block hits to the samples
+#[derive(Debug, Clone, Default)]
+pub struct SampleSheet {
+    /// the Index blocks keying the samples, the columns of the sample sheet
+    pub index_names: Vec<String>,
+    /// the hits of the index_names in order: sample
+    pub samples: HashMap<Vec<String>, Sample>,
+}
+
+impl SampleSheet {
+    pub fn from_file(file_path: &str, index_names: &[String]) -> Result<Self> {
+        let mut sheet_str = String::new();
+        File::open(file_path)?.read_to_string(&mut sheet_str)?;
+        Self::from_tsv(&sheet_str, index_names)
+    }
+
+    /// read the tsv with a `sample` column, the columns named after the Index blocks hold
+    /// their hits (the fasta_seq_id), the others are the metadata
+    pub fn from_tsv(sheet_str: &str, index_names: &[String]) -> Result<Self> {
+        let mut rdr = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(sheet_str.as_bytes());
+        let headers: Vec<String> = rdr.headers()?.iter().map(|x| x.to_string()).collect();
+        if !headers.iter().any(|x| x == "sample") {
+            bail!("the sample column is missing in the sample sheet");
+        }
+        let index_names: Vec<String> = headers
+            .iter()
+            .filter(|x| index_names.contains(x))
+            .cloned()
+            .collect();
+        if index_names.is_empty() {
+            bail!("no column of the sample sheet is named after an Index block");
+        }
+        let mut samples = HashMap::new();
+        for result in rdr.records() {
+            let record = result?;
+            let meta: HashMap<String, String> = headers
+                .iter()
+                .cloned()
+                .zip(record.iter().map(|x| x.to_string()))
+                .collect();
+            let key: Vec<String> = index_names.iter().map(|x| meta[x].clone()).collect();
+            let sample = Sample {
+                name: meta["sample"].clone(),
+                meta,
+            };
+            if let Some(pre) = samples.insert(key.clone(), sample) {
+                bail!("{} of {} is repeated in the sample sheet", key.join(","), pre.name);
+            }
+        }
+        Ok(SampleSheet {
+            index_names,
+            samples,
+        })
+    }
+
+    /// the sample of the Index block hits (block name: best_index)
+    pub fn find(&self, hits: &HashMap<String, String>) -> Option<&Sample> {
+        let key: Option<Vec<String>> = self
+            .index_names
+            .iter()
+            .map(|x| hits.get(x).cloned())
+            .collect();
+        self.samples.get(&key?)
+    }
+
+    /// whether every Index block of the sample sheet is found
+    pub fn is_complete(&self, hits: &HashMap<String, String>) -> bool {
+        self.index_names.iter().all(|x| hits.contains_key(x))
+    }
+}
+
+#[test]
+fn test_sample_sheet() {
+    let sheet_str = "sample	i7	i5	project
+s1	A1	B1	p1
+s2	A1	B2	p2";
+    let index_names = vec!["i7".to_string(), "i5".to_string(), "other".to_string()];
+    let sheet = SampleSheet::from_tsv(sheet_str, &index_names).unwrap();
+    assert_eq!(sheet.index_names, vec!["i7", "i5"]);
+    let hits: HashMap<String, String> = [("i7", "A1"), ("i5", "B2")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let sample = sheet.find(&hits).unwrap();
+    assert_eq!((sample.name.as_str(), sample.meta["project"].as_str()), ("s2", "p2"));
+    let hits: HashMap<String, String> = [("i7", "A2"), ("i5", "B2")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    assert!(sheet.find(&hits).is_none() && sheet.is_complete(&hits));
+    let hits: HashMap<String, String> = [("i7".to_string(), "A1".to_string())].into();
+    assert!(sheet.find(&hits).is_none() && !sheet.is_complete(&hits));
+}
@@ -123,6 +123,212 @@ fn test_revcomp() {
     // dbg!(rc_seq);
 }
 
+/// the bases matched by the IUPAC code, empty for the unknown code
+pub fn iupac_bases(code: u8) -> &'static [u8] {
+    match code.to_ascii_uppercase() {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' | b'U' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGT",
+        _ => b"",
+    }
+}
+
+/// check if the read base is compatible with the IUPAC code of the block sequence
+pub fn iupac_match(code: u8, base: u8) -> bool {
+    base == MASK_BASE
+        || code.eq_ignore_ascii_case(&base)
+        || iupac_bases(code).contains(&base.to_ascii_uppercase())
+}
+
+/// the read base masked for its low quality, it matches any base of the block sequence
+pub const MASK_BASE: u8 = b'*';
+
+/// the read base hidden from the block search, e.g. a found occurrence of the concatemer,
+/// it matches no base of the block sequence
+pub const HIDDEN_BASE: u8 = b'-';
+
+/// mask the read bases whose phred quality (offset 33) is below `min_qual`
+pub fn mask_low_qual(seq: &[u8], qual: &[u8], min_qual: u8) -> Vec<u8> {
+    seq.iter()
+        .zip(qual.iter())
+        .map(|(&base, &q)| {
+            if base != HIDDEN_BASE && q.saturating_sub(33) < min_qual {
+                MASK_BASE
+            } else {
+                base
+            }
+        })
+        .collect()
+}
+
+/// the max fraction of the mismatched bases in the overlap of a merged pair
+const MERGE_MAX_DIFF_RATE: f64 = 0.1;
+/// the score of an overlap is the matched bases minus this penalty for each mismatch
+const MERGE_MISMATCH_PENALTY: isize = 4;
+
+/// merge the read 1 and the reverse complement of the read 2 by their best overlap
+/// (at least `min_overlap` bases), the mismatch in the overlap takes the base of higher
+/// quality, None if the pair doesn't overlap. If the insert is shorter than the reads,
+/// both read through into the adapters and only the overlap is kept; a read-through
+/// overlap is only taken if it beats the best forward one by `min_overlap`
+pub fn merge_pair(
+    seq1: &[u8],
+    qual1: &[u8],
+    seq2: &[u8],
+    qual2: &[u8],
+    min_overlap: usize,
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let seq2 = revcomp(seq2);
+    let qual2: Vec<u8> = qual2.iter().rev().cloned().collect();
+    let min_overlap = min_overlap.max(1);
+    // the start of the overlap on the read 1 and the read 2 at `shift`
+    let starts = |shift: isize| {
+        if shift >= 0 {
+            (shift as usize, 0)
+        } else {
+            (0, shift.unsigned_abs())
+        }
+    };
+    // the best (score, overlap, shift) of the forward and the read-through overlaps,
+    // the read 2 starts at `shift` of the read 1, a negative shift means the read 2
+    // starts before the read 1
+    let mut best_forward: Option<(isize, usize, isize)> = None;
+    let mut best_through: Option<(isize, usize, isize)> = None;
+    let (len1, len2) = (seq1.len() as isize, seq2.len() as isize);
+    for shift in (min_overlap as isize - len2)..=(len1 - min_overlap as isize) {
+        let (start1, start2) = starts(shift);
+        let overlap = (seq1.len() - start1).min(seq2.len() - start2);
+        if overlap < min_overlap {
+            continue;
+        }
+        let n_diff = seq1[start1..start1 + overlap]
+            .iter()
+            .zip(seq2[start2..start2 + overlap].iter())
+            .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+            .count();
+        if n_diff as f64 > MERGE_MAX_DIFF_RATE * overlap as f64 {
+            continue;
+        }
+        // the more evidence wins, a short exact overlap doesn't beat a long one with an error
+        let score = (overlap - n_diff) as isize - MERGE_MISMATCH_PENALTY * n_diff as isize;
+        let best = if shift < 0 { &mut best_through } else { &mut best_forward };
+        if best.is_none_or(|(s, o, _)| score > s || (score == s && overlap > o)) {
+            *best = Some((score, overlap, shift));
+        }
+    }
+    let (_, overlap, shift) = match (best_forward, best_through) {
+        (Some(forward), Some(through)) if through.0 >= forward.0 + min_overlap as isize => through,
+        (Some(forward), _) => forward,
+        (None, through) => through?,
+    };
+    let (start1, start2) = starts(shift);
+
+    let mut seq = seq1[..start1].to_vec();
+    let mut qual = qual1[..start1].to_vec();
+    for ii in 0..overlap {
+        let (b1, q1) = (seq1[start1 + ii], qual1[start1 + ii]);
+        let (b2, q2) = (seq2[start2 + ii], qual2[start2 + ii]);
+        if b1.eq_ignore_ascii_case(&b2) {
+            seq.push(b1);
+            qual.push(q1.max(q2));
+        } else {
+            // the quality of the mismatch is the difference, at least 2 (#)
+            let (b, q) = if q1 >= q2 { (b1, q1 - q2) } else { (b2, q2 - q1) };
+            seq.push(b);
+            qual.push((q + 33).max(b'#'));
+        }
+    }
+    if shift < 0 {
+        // the overhangs of the read-through pair are the adapters
+    } else if start1 + overlap < seq1.len() {
+        seq.extend(&seq1[start1 + overlap..]);
+        qual.extend(&qual1[start1 + overlap..]);
+    } else {
+        seq.extend(&seq2[overlap..]);
+        qual.extend(&qual2[overlap..]);
+    }
+    Some((seq, qual))
+}
+
+#[test]
+fn test_merge_pair() {
+    let insert = b"AAACCTGAGAAACCATGTCACGGGTATATGAGTTCA";
+    let seq1 = &insert[..24];
+    let mut seq2 = revcomp(&insert[12..]);
+    // a low quality mismatch of read 2 in the overlap
+    seq2[20] = b'C';
+    let mut qual2 = b"I".repeat(seq2.len());
+    qual2[20] = b'#';
+    let qual1 = b"I".repeat(seq1.len());
+    let (seq, qual) = merge_pair(seq1, &qual1, &seq2, &qual2, 10).unwrap();
+    assert_eq!(seq, insert.to_vec());
+    assert_eq!(qual.len(), insert.len());
+    assert!(merge_pair(seq1, &qual1, &seq2, &qual2, 13).is_none());
+
+    // the insert is shorter than the reads, both read through into the adapters
+    let insert = b"AAACCTGAGAAACCATGTCA";
+    let seq1 = [&insert[..], b"AGATCGGAAG"].concat();
+    let seq2 = [&revcomp(insert)[..], b"CTGTCTCTTA"].concat();
+    let qual = b"I".repeat(seq1.len());
+    let (seq, qual) = merge_pair(&seq1, &qual, &seq2, &qual, 10).unwrap();
+    assert_eq!(seq, insert.to_vec());
+    assert_eq!(qual.len(), insert.len());
+
+    // the insert ends with the start of the read 1, the short exact read-through
+    // overlap doesn't beat the real overlap of 60 bases with an error
+    let body = b"GCTAAAGACAATTACATAACATACACGTCAGCACGAAACTTGTTGGCCCAGTGTGAATCGCTTAAGGGTTAAGTAAGTGTGATGCATACGCCTTTACTTGCTGTGTCCACCCCATCGGACTGGCATTT";
+    let insert = [&body[..], &body[..12]].concat();
+    let seq1 = &insert[..100];
+    let mut seq2 = revcomp(&insert[40..]);
+    seq2[40] = if seq2[40] == b'A' { b'C' } else { b'A' };
+    let qual = b"I".repeat(100);
+    let (seq, _) = merge_pair(seq1, &qual, &seq2, &qual, 10).unwrap();
+    assert_eq!(seq.len(), insert.len());
+}
+
+/// the read name without the mate suffix, e.g. `/1`, `/2` or `/3`
+pub fn read_name(id: &str) -> &str {
+    match id.rsplit_once('/') {
+        Some((name, mate)) if !mate.is_empty() && mate.bytes().all(|x| x.is_ascii_digit()) => {
+            name
+        }
+        _ => id,
+    }
+}
+
+#[test]
+fn test_read_name() {
+    assert_eq!(read_name("read1/1"), "read1");
+    assert_eq!(read_name("read1/3"), "read1");
+    assert_eq!(read_name("read1"), "read1");
+    assert_eq!(read_name("a/b"), "a/b");
+}
+
+#[test]
+fn test_iupac_match() {
+    assert!(iupac_match(b'N', b'A'));
+    assert!(iupac_match(b'r', b'G'));
+    assert!(!iupac_match(b'R', b'C'));
+    assert!(iupac_match(b'A', b'a'));
+    assert!(!iupac_match(b'A', b'N'));
+    assert!(iupac_match(b'A', MASK_BASE));
+    // the hidden occurrence of a concatemer matches no code, nor is it masked
+    assert!(!iupac_match(b'N', HIDDEN_BASE));
+    assert_eq!(mask_low_qual(b"A-", b"##", 20), b"*-");
+}
+
 /// union multiple Range
 pub fn union(ranges: Vec<std::ops::Range<i32>>) -> Vec<std::ops::Range<i32>> {
     let mut sorted_ranges = ranges.clone();
@@ -1,5 +1,5 @@
 use crate::blockinfo::get_block_info_fasta;
-use crate::readblockalign::ReadBlockAlign;
+use crate::readblockalign::{ReadAlignOpts, ReadBlockAlign};
 use bio::io::fastq::{self, Record};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
@@ -15,7 +15,8 @@ pub fn read_align(seq_info: &str, blockinfo_str: &str, fasta_file: &str) -> JsVa
     let mut read_block_align_vec = vec![];
     records.into_iter().for_each(|record| {
         let record = record.unwrap();
-        let read_block_align = ReadBlockAlign::read_block_info(&record, &block_info_vec);
+        let read_block_align =
+            ReadBlockAlign::read_block_info(&record, &block_info_vec, &ReadAlignOpts::default());
         read_count += 1;
         read_block_align_vec.push(read_block_align.to_pretty());
     });